@@ -42,18 +42,21 @@ struct TestOutput {
     status: i32
 }
 
-fn run_test<P: Into<PathBuf> + std::convert::AsRef<std::ffi::OsStr>>(f_in: PathBuf, f_out: &PathBuf, compiler: P, compile_mode: bool, stdin: String) -> Result<TestOutput> {
+fn run_test<P: Into<PathBuf> + std::convert::AsRef<std::ffi::OsStr>>(f_in: PathBuf, f_out: &PathBuf, compiler: P, compile_mode: bool, stdin: String, includes: &[&str]) -> Result<TestOutput> {
     let mut command = process::Command::new(compiler);
     command.stdout(Stdio::piped());
     command.stderr(Stdio::piped());
     if compile_mode {
-        command.arg("-cqr");
+        command.arg("-qr");
     } else {
         command.arg("-sq");
     }
 
-    command.arg("-i");
     command.arg(f_in);
+    for include in includes {
+        command.arg("-I");
+        command.arg(include);
+    }
     command.arg("-o");
     command.arg(f_out);
 
@@ -80,16 +83,21 @@ fn run_test<P: Into<PathBuf> + std::convert::AsRef<std::ffi::OsStr>>(f_in: PathB
 
 fn run_tests(args: Args) -> Result<()>{
 
-    let files = fs::read_dir(args.input)?;
+    let files = fs::read_dir(&args.input)?;
+    // tests can include the std and each other
+    let includes = ["./include", args.input.as_str()];
 
     for file in  files {
         let file = file?;
+        if file.path().extension().is_none_or(|e| e != "mcl") {
+            continue;
+        }
         let f_name = file.file_name().to_string_lossy().to_string();
         let f_out = PathBuf::from(&args.output).join(f_name);
 
 
-        let intp = run_test(file.path(), &f_out, &args.compiler_path, false, String::new())?;
-        let comp = run_test(file.path(), &f_out, &args.compiler_path, true, String::new())?;
+        let intp = run_test(file.path(), &f_out, &args.compiler_path, false, String::new(), &includes)?;
+        let comp = run_test(file.path(), &f_out, &args.compiler_path, true, String::new(), &includes)?;
         compare_results(&intp, &comp, &file.path())?;
     }
 
@@ -133,7 +141,7 @@ struct Args {
     mode: String,
     
     /// Use compile mode
-    #[arg(long, short)]
+    #[arg(long)]
     compile: bool,
     
     /// Use interpret mode
@@ -3,7 +3,7 @@ use std::process::{Command, Stdio};
 use anyhow::Result;
 use crate::{info, error};
 
-pub fn linux_x86_64_compile_and_link(of_a: &Path, of_o: &Path, of_c: &Path, quiet: bool) -> Result<()> {
+pub fn linux_x86_64_assemble(of_a: &Path, of_o: &Path, quiet: bool) -> Result<()> {
     
     let nasm_args = [
         "-felf64",
@@ -12,13 +12,6 @@ pub fn linux_x86_64_compile_and_link(of_a: &Path, of_o: &Path, of_c: &Path, quie
         of_o.to_str().unwrap()
     ];
 
-    let ld_args = [
-        of_o.to_str().unwrap(),
-        "-o",
-        of_c.to_str().unwrap()
-    ];
-
-
     let mut proc = if cfg!(target_os = "windows") {
         return Ok(());
     } else {
@@ -42,6 +35,16 @@ pub fn linux_x86_64_compile_and_link(of_a: &Path, of_o: &Path, of_c: &Path, quie
         info!("nasm process exited with code {}", exit);
     }
 
+    Ok(())
+}
+
+pub fn linux_x86_64_link(of_o: &Path, of_c: &Path, quiet: bool) -> Result<()> {
+
+    let ld_args = [
+        of_o.to_str().unwrap(),
+        "-o",
+        of_c.to_str().unwrap()
+    ];
 
     let mut proc2 = if cfg!(target_os = "windows") {
         return Ok(());
@@ -1,6 +1,7 @@
 use std::{fs, path::PathBuf, io::{Write, BufWriter}, collections::HashMap};
-use crate::{definitions::*, Args, warn, lerror};
-use crate::compile::commands::linux_x86_64_compile_and_link;
+use crate::{definitions::*, Args, warn, lerror, coverage};
+use crate::compile::commands::{linux_x86_64_assemble, linux_x86_64_link};
+use crate::emit::Emit;
 use crate::definitions::InstructionType;
 use super::{commands::linux_x86_64_run, Constant, Memory, Function};
 
@@ -25,7 +26,17 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
     of_o.set_extension("o");
     of_a.set_extension("nasm");
 
+    // requested outputs go next to the output file instead of /tmp
+    if args.emits(Emit::Asm) {
+        of_a = Emit::Asm.path(args);
+    }
+    if args.emits(Emit::Obj) {
+        of_o = Emit::Obj.path(args);
+    }
+
     let mut should_push_ret = false;
+    // size of the local memories of the function we are currently in
+    let mut frame_size = 0;
 
     let file = fs::File::create(&of_a)?;
     let mut writer = BufWriter::new(&file);
@@ -33,9 +44,12 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
     let mut constants:  HashMap<String, Constant> = HashMap::new();
     let mut functions: Vec<Function> = Vec::new();
 
-    let mut alloced_structs: Vec<(String, String)> = Vec::new();
     // println!("{}", tokens.len());
     let mut strings: Vec<String> = Vec::new();
+    // the ip of the 'match' and the target of every value from the smallest case on
+    let mut jump_tables: Vec<(usize, Vec<usize>)> = Vec::new();
+    // const ops use the constants name, which is not its key if it came from a namespaced include
+    let const_values: HashMap<&str, &ConstValue> = program.constants.values().map(|c| (c.name.as_str(), &c.value)).collect();
     
     writeln!(writer, "BITS 64")?;
     writeln!(writer, "segment .text")?;
@@ -53,6 +67,14 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
     }
 
 
+    let cov_blocks = if args.coverage {
+        coverage::blocks(&program.ops)
+    } else {
+        Vec::new()
+    };
+    // op index => counter index
+    let cov_ids: HashMap<usize, usize> = cov_blocks.iter().enumerate().map(|(i, b)| (b.ip, i)).collect();
+
     let mut ti = 0;
     while ti < program.ops.len() {
         let token = &program.ops[ti];
@@ -66,25 +88,19 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
                 writeln!(writer, "    ;; -- {:?}", token.typ)?;
             }
         } else {
-            if ti > 0 {
-                if program.ops[ti-1].typ == OpType::Keyword(KeywordType::Else) ||
-                program.ops[ti-1].typ == OpType::Keyword(KeywordType::End){
-                    writeln!(writer, "addr_{ti}:")?;
-                }
-            }
-
-            if ti + 1 < program.ops.len() && program.ops[ti+1].typ == OpType::Keyword(KeywordType::End) {
+            // only the ops something can jump to get a label, nested blocks can ask for the same one twice
+            let after_block = ti > 0 && matches!(program.ops[ti-1].typ, OpType::Keyword(KeywordType::Else | KeywordType::Elif | KeywordType::End));
+            let before_end = ti + 1 < program.ops.len() && program.ops[ti+1].typ == OpType::Keyword(KeywordType::End);
+            let is_target = matches!(token.typ, OpType::Keyword(KeywordType::End | KeywordType::While | KeywordType::Case | KeywordType::Default));
+            if after_block || before_end || is_target {
                 writeln!(writer, "addr_{ti}:")?;
             }
-            
-            if let OpType::Keyword(keyword) = &token.typ {
-                match keyword {
-                    &KeywordType::End |
-                    &KeywordType::While => {
-                        writeln!(writer, "addr_{ti}:")?;
-                    }
-                    _ => ()
-                }
+        }
+
+        // functions get counted after their prologue
+        if !matches!(token.typ, OpType::Keyword(KeywordType::FunctionDef | KeywordType::FunctionDefExported)) {
+            if let Some(id) = cov_ids.get(&ti) {
+                writeln!(writer, "    OP_CovCount {id}")?;
             }
         }
 
@@ -111,8 +127,13 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
                         writeln!(writer, "    OP_Drop")?;
                         ti += 1;
                     },
+                    //? the typechecker sets value to 1 for these if they work on signed ints
                     InstructionType::Print => {
-                        writeln!(writer, "    OP_Print")?;
+                        if token.value == 1 {
+                            writeln!(writer, "    OP_PrintSigned")?;
+                        } else {
+                            writeln!(writer, "    OP_Print")?;
+                        }
                         ti += 1;
                     },
         
@@ -160,6 +181,23 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
                         writeln!(writer, "    OP_Store64")?;
                         ti += 1;
                     }
+                    InstructionType::Index => {
+                        index_address(&mut writer, token, ti, debug, &mut strings)?;
+                        writeln!(writer, "    push rax")?;
+                        ti += 1;
+                    }
+                    InstructionType::IndexRead => {
+                        index_address(&mut writer, token, ti, debug, &mut strings)?;
+                        writeln!(writer, "    {}", load(&token.text))?;
+                        writeln!(writer, "    push rbx")?;
+                        ti += 1;
+                    }
+                    InstructionType::IndexWrite => {
+                        writeln!(writer, "    pop rcx")?;
+                        index_address(&mut writer, token, ti, debug, &mut strings)?;
+                        writeln!(writer, "    {}", store(&token.text))?;
+                        ti += 1;
+                    }
         
                     // math
                     InstructionType::Plus => {
@@ -175,11 +213,11 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
                         ti += 1;
                     },
                     InstructionType::Lt => {
-                        writeln!(writer, "    OP_Lt")?;
+                        writeln!(writer, "    OP_Lt {}", if token.value == 1 {"l"} else {"b"})?;
                         ti += 1;
                     },
                     InstructionType::Gt => {
-                        writeln!(writer, "    OP_Gt")?;
+                        writeln!(writer, "    OP_Gt {}", if token.value == 1 {"g"} else {"a"})?;
                         ti += 1;
                     },
                     InstructionType::NotEquals => {
@@ -187,11 +225,11 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
                         ti += 1;
                     },
                     InstructionType::Le => {
-                        writeln!(writer, "    OP_Le")?;
+                        writeln!(writer, "    OP_Le {}", if token.value == 1 {"le"} else {"be"})?;
                         ti += 1;
                     },
                     InstructionType::Ge => {
-                        writeln!(writer, "    OP_Ge")?;
+                        writeln!(writer, "    OP_Ge {}", if token.value == 1 {"ge"} else {"ae"})?;
                         ti += 1;
                     },
                     InstructionType::Band => {
@@ -203,7 +241,7 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
                         ti += 1;
                     },
                     InstructionType::Shr => {
-                        writeln!(writer, "    OP_Shr")?;
+                        writeln!(writer, "    OP_Shr {}", if token.value == 1 {"sar"} else {"shr"})?;
                         ti += 1;
                     },
                     InstructionType::Shl => {
@@ -211,51 +249,59 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
                         ti += 1;
                     },
                     InstructionType::DivMod => {
-                        writeln!(writer, "    OP_DivMod")?;
+                        if token.value == 1 {
+                            writeln!(writer, "    OP_IDivMod")?;
+                        } else {
+                            writeln!(writer, "    OP_DivMod")?;
+                        }
                         ti += 1;
                     },
                     InstructionType::Mul => {
                         writeln!(writer, "    OP_Mul")?;
                         ti += 1;
                     },
-                    InstructionType::Syscall0 => {
-                        writeln!(writer, "    OP_Syscall0")?;
-                        ti += 1;
-                    },
-                    InstructionType::Syscall1 => {
-                        writeln!(writer, "    OP_Syscall1")?;
-                        ti += 1;
-                    },
-                    InstructionType::Syscall2 => {
-                        writeln!(writer, "    OP_Syscall2")?;
-                        ti += 1;
-                    },
-                    InstructionType::Syscall3 => {
-                        writeln!(writer, "    OP_Syscall3")?;
-                        ti += 1;
-                    },
-                    InstructionType::Syscall4 => {
-                        writeln!(writer, "    OP_Syscall4")?;
-                        ti += 1;
-                    },
-                    InstructionType::Syscall5 => {
-                        writeln!(writer, "    OP_Syscall5")?;
-                        ti += 1;
-                    },
+                    InstructionType::Syscall0 |
+                    InstructionType::Syscall1 |
+                    InstructionType::Syscall2 |
+                    InstructionType::Syscall3 |
+                    InstructionType::Syscall4 |
+                    InstructionType::Syscall5 |
                     InstructionType::Syscall6 => {
-                        writeln!(writer, "    OP_Syscall6")?;
+                        // programs can also end with an exit syscall, the counters have to be written before it
+                        if args.coverage {
+                            writeln!(writer, "    OP_CovExit")?;
+                        }
+                        writeln!(writer, "    OP_{instruction:?}")?;
                         ti += 1;
                     },
                     InstructionType::MemUse => {
                         writeln!(writer, "    OP_MemUse {}", token.addr.unwrap())?;
                         ti += 1;
                     },
+                    InstructionType::LocalMemUse => {
+                        writeln!(writer, "    OP_LocalMemUse {}", frame_size - token.addr.unwrap())?;
+                        ti += 1;
+                    },
                     InstructionType::None => {
                         println!("{token:?}");
                         unreachable!()
                     },
                     InstructionType::FnCall => {
-                        writeln!(writer, "    OP_FnCall {}", token.text)?;
+                        // the callee can reuse our return stack slot if nothing runs after the call,
+                        // local memories could still be referenced by the callee so keep those frames
+                        if !debug && frame_size == 0 && !should_push_ret && is_tail_call(&program.ops, ti) {
+                            writeln!(writer, "    OP_TailCall {}", token.text)?;
+                        } else {
+                            writeln!(writer, "    OP_FnCall {}", token.text)?;
+                        }
+                        ti += 1;
+                    },
+                    InstructionType::FnAddr => {
+                        writeln!(writer, "    OP_FnAddr {}", token.text)?;
+                        ti += 1;
+                    },
+                    InstructionType::Call => {
+                        writeln!(writer, "    OP_Call")?;
                         ti += 1;
                     },
                     InstructionType::Return => {
@@ -266,27 +312,57 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
                             should_push_ret = false;
                         }
 
-                        writeln!(writer, "    sub rbp, 8")?;
+                        writeln!(writer, "    sub rbp, {}", 8 + frame_size)?;
                         writeln!(writer, "    mov rbx, qword [rbp]")?;
                         writeln!(writer, "    push rbx")?;
                         writeln!(writer, "    ret")?;
                         ti += 1;
                     },
+                    InstructionType::CastSized => {
+                        //? narrowing casts cut the value down to its size, u64 and i64 stay as they are
+                        let truncate = match token.text.as_str() {
+                            "cast(u8)" => Some("movzx rax, al"),
+                            "cast(u16)" => Some("movzx rax, ax"),
+                            "cast(u32)" => Some("mov eax, eax"),
+                            "cast(i8)" => Some("movsx rax, al"),
+                            "cast(i16)" => Some("movsx rax, ax"),
+                            "cast(i32)" => Some("movsxd rax, eax"),
+                            _ => None
+                        };
+                        if let Some(truncate) = truncate {
+                            writeln!(writer, "    OP_Truncate {truncate}")?;
+                        }
+                        ti += 1;
+                    },
                     InstructionType::CastBool |
                     InstructionType::CastPtr |
                     InstructionType::CastInt |
                     InstructionType::CastVoid |
+                    InstructionType::CastEnum |
+                    InstructionType::CastStruct |
+                    InstructionType::CastFn |
+                    InstructionType::CastArray |
                     InstructionType::TypeBool |
                     InstructionType::TypePtr |
                     InstructionType::TypeInt |
+                    InstructionType::TypeSized |
                     InstructionType::TypeVoid |
                     InstructionType::TypeAny |
+                    InstructionType::TypeEnum |
+                    InstructionType::TypeStruct |
+                    InstructionType::TypeFn |
+                    InstructionType::TypeArray |
+                    InstructionType::TypeVar |
                     InstructionType::Returns |
                     InstructionType::With => {
                         ti += 1;
                     }
                     InstructionType::ConstUse => {
-                        writeln!(writer, "    OP_ConstUse {}", token.text)?;
+                        match const_values.get(token.text.as_str()).copied() {
+                            Some(ConstValue::Str(s)) => writeln!(writer, "    OP_PushStr {}, const_{}", s.len(), token.text)?,
+                            Some(ConstValue::CStr(_)) => writeln!(writer, "    OP_PushCStr const_{}", token.text)?,
+                            _ => writeln!(writer, "    OP_ConstUse {}", token.text)?
+                        }
 
                         let mut c = constants.get(&token.text).unwrap().clone();
                         c.used = true;
@@ -294,8 +370,31 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
                         constants.insert(token.text.clone(), c);
                         ti += 1;
                     },
-                    InstructionType::StructUse => {
-                        writeln!(writer, "    OP_StructUse {}", token.text)?;
+                    InstructionType::InlineAsm => {
+                        for line in token.text.lines() {
+                            writeln!(writer, "{line}")?;
+                        }
+                        // skip the signature and the interpreter fallback
+                        ti = token.jmp;
+                    },
+                    InstructionType::Field => {
+                        if token.value != 0 {
+                            writeln!(writer, "    pop rax")?;
+                            writeln!(writer, "    add rax, {}", token.value)?;
+                            writeln!(writer, "    push rax")?;
+                        }
+                        ti += 1;
+                    }
+                    InstructionType::FieldRead => {
+                        writeln!(writer, "    pop rax")?;
+                        writeln!(writer, "    {}", load(&token.text))?;
+                        writeln!(writer, "    push rbx")?;
+                        ti += 1;
+                    }
+                    InstructionType::FieldWrite => {
+                        writeln!(writer, "    pop rcx")?;
+                        writeln!(writer, "    pop rax")?;
+                        writeln!(writer, "    {}", store(&token.text))?;
                         ti += 1;
                     },
                 }
@@ -313,13 +412,63 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
                         writeln!(writer, "    jz addr_{}", token.jmp)?;
                         ti += 1;
                     }
-                    KeywordType::Else => {
+                    KeywordType::Else |
+                    KeywordType::Elif => {
                         writeln!(writer, "    jmp addr_{}", token.jmp)?;
                         ti += 1;
                     },
                     KeywordType::While => {
                         ti += 1;
                     }
+                    KeywordType::Match => {
+                        // the arms are chained through their 'end's, without a default we leave the 'match'
+                        let mut cases: Vec<(usize, usize)> = Vec::new();
+                        let mut default = token.jmp;
+                        let mut i = ti + 1;
+                        loop {
+                            match program.ops[i].typ {
+                                OpType::Keyword(KeywordType::Case) => cases.push((program.ops[i].value, i)),
+                                OpType::Keyword(KeywordType::Default) => default = i,
+                                _ => break
+                            }
+                            i = program.ops[i].jmp + 1;
+                        }
+
+                        writeln!(writer, "    pop rax")?;
+                        let min = cases.iter().map(|c| c.0).min().unwrap_or(0);
+                        let max = cases.iter().map(|c| c.0).max().unwrap_or(0);
+                        if cases.len() >= 4 && max - min < cases.len() * 2 {
+                            // dense enough for a jump table, the gaps go to the default
+                            let mut table = vec![default; max - min + 1];
+                            for (value, ip) in &cases {
+                                table[value - min] = *ip;
+                            }
+                            writeln!(writer, "    mov rbx, {min}")?;
+                            writeln!(writer, "    sub rax, rbx")?;
+                            writeln!(writer, "    cmp rax, {}", max - min)?;
+                            writeln!(writer, "    ja addr_{default}")?;
+                            writeln!(writer, "    lea rbx, [rel match_{ti}]")?;
+                            writeln!(writer, "    jmp [rbx + rax * 8]")?;
+                            jump_tables.push((ti, table));
+                        } else {
+                            for (value, ip) in &cases {
+                                writeln!(writer, "    mov rbx, {value}")?;
+                                writeln!(writer, "    cmp rax, rbx")?;
+                                writeln!(writer, "    je addr_{ip}")?;
+                            }
+                            writeln!(writer, "    jmp addr_{default}")?;
+                        }
+                        ti += 1;
+                    }
+                    KeywordType::Case |
+                    KeywordType::Default => {
+                        ti += 1;
+                    }
+                    KeywordType::Break |
+                    KeywordType::Continue => {
+                        writeln!(writer, "    jmp addr_{}", token.jmp)?;
+                        ti += 1;
+                    }
                     KeywordType::End => {
                         if ti + 1 != token.jmp {
                             writeln!(writer, "    jmp addr_{}", token.jmp)?;
@@ -331,13 +480,16 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
                         ti += 1;
                     }
                     KeywordType::ConstantDef => {
-                        // TODO: after we add c style strings add supoort for them in constants
                         let a = args.get_opt_level()? < 1;
+                        let value_s = match const_values.get(token.text.as_str()).copied() {
+                            Some(ConstValue::Str(s) | ConstValue::CStr(s)) => Some(s.clone()),
+                            _ => None
+                        };
                         let c = Constant{
                             loc: token.loc.clone(),
                             name: token.text.clone(),
-                            value_i: Some(token.value),
-                            value_s: None,
+                            value_i: if value_s.is_none() { Some(token.value) } else { None },
+                            value_s,
                             used: a,
                         };
                         
@@ -345,10 +497,12 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
                         ti += 1;
                     },
                     KeywordType::FunctionDef => {
+                        frame_size = token.value;
                         writeln!(writer, "{}:", token.text)?;
-                        writeln!(writer, "    pop rbx")?;
-                        writeln!(writer, "    mov qword [rbp], rbx")?;
-                        writeln!(writer, "    add rbp, 8")?;
+                        enter_frame(&mut writer, token, ti, debug, &mut strings)?;
+                        if let Some(id) = cov_ids.get(&ti) {
+                            writeln!(writer, "    OP_CovCount {id}")?;
+                        }
                         functions.push(Function { loc: token.loc.clone(), name: token.text.clone(), exter: false});
                         ti += 1;
                     },
@@ -359,7 +513,7 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
                             should_push_ret = false;
                         }
 
-                        writeln!(writer, "    sub rbp, 8")?;
+                        writeln!(writer, "    sub rbp, {}", 8 + frame_size)?;
                         writeln!(writer, "    mov rbx, qword [rbp]")?;
                         writeln!(writer, "    push rbx")?;
                         writeln!(writer, "    ret")?;
@@ -373,12 +527,13 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
                             bail!("");
                         }
 
+                        frame_size = token.value;
                         writeln!(writer, "global {}", token.text)?;
                         writeln!(writer, "{}:", token.text)?;
-                        
-                        writeln!(writer, "    pop rbx")?;
-                        writeln!(writer, "    mov qword [rbp], rbx")?;
-                        writeln!(writer, "    add rbp, 8")?;
+                        enter_frame(&mut writer, token, ti, debug, &mut strings)?;
+                        if let Some(id) = cov_ids.get(&ti) {
+                            writeln!(writer, "    OP_CovCount {id}")?;
+                        }
                         warn!("External functions are highly experimental and should be treated as such");
                         if token.types.0 == 0 {
                             writeln!(writer, "    ; no arguments")?;
@@ -422,16 +577,22 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
                     KeywordType::Inline |
                     KeywordType::Export |
                     KeywordType::Struct |
+                    KeywordType::Asm |
+                    KeywordType::Macro |
+                    KeywordType::CondIf |
+                    KeywordType::CondThen |
+                    KeywordType::CondElse |
+                    KeywordType::CondEnd |
+                    KeywordType::Priv |
+                    KeywordType::Enum |
+                    KeywordType::Alloc |
                     KeywordType::Constant => unreachable!(),
                 }
             }
             OpType::Internal(t) => {
                 match t {
-                    InternalType::StructAlloc{name} => {
-                        alloced_structs.push((name, token.text.clone()));
-                        ti += 1;
-                    },
-                    InternalType::Arrow => panic!("{t:?}"),
+                    InternalType::Arrow |
+                    InternalType::DocComment => panic!("{t:?}"),
                 }
             },
         }
@@ -439,17 +600,45 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
     writeln!(writer, "addr_{ti}:")?;
     if !crate::config::ENABLE_EXPORTED_FUNCTIONS && !args.lib_mode {
         writeln!(writer, "end:")?;
+        if args.coverage {
+            writeln!(writer, "    call cov_dump")?;
+        }
         writeln!(writer, "    mov rax, 60")?;
         writeln!(writer, "    mov rdi, 0")?;
         writeln!(writer, "    syscall")?;
     }
+    if args.coverage {
+        writeln!(writer, "cov_dump:")?;
+        writeln!(writer, "    OP_CovDump {}", cov_blocks.len() * 8)?;
+        writeln!(writer, "    ret")?;
+    }
+    if debug {
+        // rsi and rdx are the message of the failed check
+        writeln!(writer, "check_failed:")?;
+        writeln!(writer, "    mov rax, 1")?;
+        writeln!(writer, "    mov rdi, 2")?;
+        writeln!(writer, "    syscall")?;
+        writeln!(writer, "    mov rax, 60")?;
+        writeln!(writer, "    mov rdi, 1")?;
+        writeln!(writer, "    syscall")?;
+    }
     writeln!(writer, "segment .data")?;
     for (i, s) in strings.iter().enumerate() {
-        let s_chars = s.chars().map(|c| (c as u32).to_string()).collect::<Vec<String>>();
+        let s_chars = s.bytes().map(|c| c.to_string()).collect::<Vec<String>>();
+        if s_chars.is_empty() {
+            // nasm wants at least one byte, the length pushed is still 0
+            writeln!(writer, "    str_{i}: db 0")?;
+            continue;
+        }
         let s_list = s_chars.join(",");
         writeln!(writer, "    str_{}: db {} ; {}", i, s_list, s.escape_default())?;
     }
     
+    for (ip, table) in &jump_tables {
+        let targets = table.iter().map(|t| format!("addr_{t}")).collect::<Vec<String>>();
+        writeln!(writer, "    match_{ip}: dq {}", targets.join(","))?;
+    }
+
     for (_, c) in constants {
         if !c.used {
             continue;
@@ -457,42 +646,42 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
 
         if let Some(v) = &c.value_i {
             writeln!(writer, "    const_{}: dq {}", c.name, v)?;
-        } else if let Some(_v) = &c.value_s {
-            todo!();
+        } else if let Some(v) = &c.value_s {
+            let bytes = v.bytes().map(|c| c.to_string()).collect::<Vec<String>>();
+            if bytes.is_empty() {
+                writeln!(writer, "    const_{}: db 0", c.name)?;
+            } else {
+                writeln!(writer, "    const_{}: db {} ; {}", c.name, bytes.join(","), v.escape_default())?;
+            }
         } else {
             unreachable!();
         }
     }
     
     
+    if args.coverage {
+        // every run appends its counters to the dump, `mclangc cov` merges them
+        let dump = std::env::current_dir()?.join(&of_c).with_extension("cov");
+        let dump = dump.to_string_lossy().bytes().map(|c| c.to_string()).collect::<Vec<String>>();
+        writeln!(writer, "    cov_file: db {},0", dump.join(","))?;
+        coverage::write_map(&of_c.with_extension("covmap"), &program.ops, &cov_blocks)?;
+    }
+    
     writeln!(writer, "segment .bss")?;
+    if args.coverage {
+        writeln!(writer, "    cov_counters: resq {}", cov_blocks.len())?;
+    }
+    // struct fields are aligned relative to the start of the memory
     for m in memories {
+        writeln!(writer, "    alignb 8")?;
         writeln!(writer, "    mem_{}: resb {}", m.id, m.size)?;
     }
     
 
-    for s in alloced_structs {
-        let Some(st) = program.struct_defs.get(&s.0) else {
-            // TODO: Make better error
-            panic!("Couldn find struct in struct defs");
-        };
-
-        let name = &s.1;
-        let mut st_size = 0;
-
-        writeln!(writer, "    struct_{name}:")?;
-        for f in &st.fields {
-            let size = f.1.get_size();
-            writeln!(writer, "    struct_{name}.{}: resb {}", f.0, size)?;
-            st_size += size;
-        }
-
-        writeln!(writer, "    struct_{name}.__size: db {}", st_size)?;
-
-    }
-    
-
-    writeln!(writer, "    ret_stack: resq 256")?;
+    // local memories live here
+    writeln!(writer, "    alignb 8")?;
+    writeln!(writer, "    ret_stack: resb {}", crate::config::RET_STACK_SZ)?;
+    writeln!(writer, "    ret_stack_end:")?;
     // for t in tokens {
     //     println!("{t:?}");
     // }
@@ -505,7 +694,17 @@ pub fn compile(program: &Program, args: &Args) -> Result<i32>{
         functions
     )?;
 
-    linux_x86_64_compile_and_link(&of_a, &of_o, &of_c, args.quiet);
+    if args.last_stage() == Emit::Asm {
+        return Ok(0);
+    }
+
+    linux_x86_64_assemble(&of_a, &of_o, args.quiet)?;
+
+    if args.last_stage() == Emit::Obj {
+        return Ok(0);
+    }
+
+    linux_x86_64_link(&of_o, &of_c, args.quiet)?;
 
     if args.run {
         let c = linux_x86_64_run(&of_c, &[], args.quiet)?;
@@ -533,4 +732,84 @@ fn pre_compile_steps(_code: &str, functions: Vec<Function>) -> Result<()> {
     }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Reads a value of the element type `elem` from rax into rbx
+fn load(elem: &str) -> &'static str {
+    match Types::from_element(elem) {
+        Types::U8 | Types::Bool => "movzx rbx, byte [rax]",
+        Types::I8 => "movsx rbx, byte [rax]",
+        Types::U16 => "movzx rbx, word [rax]",
+        Types::I16 => "movsx rbx, word [rax]",
+        Types::U32 => "mov ebx, dword [rax]",
+        Types::I32 => "movsxd rbx, dword [rax]",
+        _ => "mov rbx, qword [rax]"
+    }
+}
+
+/// Writes rcx to rax as the element type `elem`
+fn store(elem: &str) -> &'static str {
+    match Types::from_element(elem).get_size() {
+        1 => "mov byte [rax], cl",
+        2 => "mov word [rax], cx",
+        4 => "mov dword [rax], ecx",
+        _ => "mov qword [rax], rcx"
+    }
+}
+
+/// Pops an index and the array under it and leaves the address of the element in rax,
+/// debug builds check the index against the length of the array first if it is known
+fn index_address(writer: &mut impl Write, op: &Operator, ti: usize, debug: bool, strings: &mut Vec<String>) -> Result<()> {
+    writeln!(writer, "    pop rax")?;
+    writeln!(writer, "    pop rbx")?;
+    if debug && op.value != 0 {
+        let msg = format!("{}:{}:{}: Index out of bounds\n", op.loc.0, op.loc.1, op.loc.2);
+        writeln!(writer, "    mov rcx, {}", op.value)?;
+        writeln!(writer, "    cmp rax, rcx")?;
+        writeln!(writer, "    jb index_ok_{ti}")?;
+        writeln!(writer, "    mov rsi, str_{}", strings.len())?;
+        writeln!(writer, "    mov rdx, {}", msg.len())?;
+        writeln!(writer, "    jmp check_failed")?;
+        writeln!(writer, "index_ok_{ti}:")?;
+        strings.push(msg);
+    }
+    writeln!(writer, "    lea rax, [rbx + rax * {}]", Types::from_element(&op.text).get_size())?;
+    Ok(())
+}
+
+/// Saves the return address and makes room for the local memories of the function `op` defines,
+/// debug builds check that the frame still fits on the return stack, deep recursion can overflow it
+fn enter_frame(writer: &mut impl Write, op: &Operator, ti: usize, debug: bool, strings: &mut Vec<String>) -> Result<()> {
+    let size = 8 + op.value;
+    writeln!(writer, "    pop rbx")?;
+    writeln!(writer, "    mov qword [rbp], rbx")?;
+    writeln!(writer, "    add rbp, {size}")?;
+    if debug {
+        let msg = format!("{}:{}:{}: Return stack overflow in '{}'\n", op.loc.0, op.loc.1, op.loc.2, op.text);
+        writeln!(writer, "    lea rax, [rel ret_stack_end]")?;
+        writeln!(writer, "    cmp rbp, rax")?;
+        writeln!(writer, "    jbe frame_ok_{ti}")?;
+        writeln!(writer, "    mov rsi, str_{}", strings.len())?;
+        writeln!(writer, "    mov rdx, {}", msg.len())?;
+        writeln!(writer, "    jmp check_failed")?;
+        writeln!(writer, "frame_ok_{ti}:")?;
+        strings.push(msg);
+    }
+    Ok(())
+}
+
+/// Checks if the call at `ip` is the last thing that runs before the function returns,
+/// following the jumps of 'else', 'elif', 'break' and 'end' of if blocks
+fn is_tail_call(ops: &[Operator], ip: usize) -> bool {
+    let mut i = ip + 1;
+    while let Some(op) = ops.get(i) {
+        match op.typ {
+            OpType::Keyword(KeywordType::FunctionDone) |
+            OpType::Instruction(InstructionType::Return) => return true,
+            // 'end' of a while loop and 'continue' jump backwards
+            OpType::Keyword(KeywordType::Else | KeywordType::Elif | KeywordType::End | KeywordType::Break) if op.jmp > i => i = op.jmp,
+            _ => return false
+        }
+    }
+    false
+}
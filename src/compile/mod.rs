@@ -3,6 +3,7 @@ use crate::definitions::Loc;
 pub mod linux_x86_64;
 pub mod commands;
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct Constant {
     pub loc: Loc,
@@ -13,6 +14,7 @@ pub struct Constant {
     // extern: bool
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct Memory {
     pub size: usize,
@@ -20,6 +22,7 @@ pub struct Memory {
     pub id: usize
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct Function {
     pub loc: Loc,
@@ -27,7 +30,7 @@ pub struct Function {
     pub exter: bool,
 }
 
-const DBG_PRINT: &'static str = "
+const DBG_PRINT: &str = "
 _dbg_print:
     mov     r9, -3689348814741910323
     sub     rsp, 40
@@ -61,9 +64,24 @@ _dbg_print:
     syscall
     add     rsp, 40
     ret
+
+_dbg_print_signed:
+    test    rdi, rdi
+    jns     _dbg_print
+    push    rdi
+    push    '-'
+    mov     rax, 1
+    mov     rdi, 1
+    mov     rsi, rsp
+    mov     rdx, 1
+    syscall
+    pop     rax
+    pop     rdi
+    neg     rdi
+    jmp     _dbg_print
 ";
 
-const MACRO_DEFINITIONS: &'static str = "\
+const MACRO_DEFINITIONS: &str = "\
 %macro OP_PushInt 1
     mov rax, %1
     push rax
@@ -93,6 +111,11 @@ const MACRO_DEFINITIONS: &'static str = "\
     call _dbg_print
 %endmacro
 
+%macro OP_PrintSigned 0
+    pop rdi
+    call _dbg_print_signed
+%endmacro
+
 %macro OP_Dup 0
     pop rax
     push rax
@@ -186,23 +209,24 @@ const MACRO_DEFINITIONS: &'static str = "\
     push rcx
 %endmacro
 
-%macro OP_Lt 0
+; the comparisons take the condition code for signed or unsigned ints
+%macro OP_Lt 1
     mov rcx, 0
     mov rdx, 1
     pop rbx
     pop rax
     cmp rax, rbx
-    cmovl rcx, rdx
+    cmov%1 rcx, rdx
     push rcx
 %endmacro
 
-%macro OP_Gt 0
+%macro OP_Gt 1
     mov rcx, 0
     mov rdx, 1
     pop rbx
     pop rax
     cmp rax, rbx
-    cmovg rcx, rdx
+    cmov%1 rcx, rdx
     push rcx
 %endmacro
 
@@ -216,23 +240,23 @@ const MACRO_DEFINITIONS: &'static str = "\
     push rcx
 %endmacro
 
-%macro OP_Le 0
+%macro OP_Le 1
     mov rcx, 0
     mov rdx, 1
     pop rbx
     pop rax
     cmp rax, rbx
-    cmovle rcx, rdx
+    cmov%1 rcx, rdx
     push rcx
 %endmacro
 
-%macro OP_Ge 0
+%macro OP_Ge 1
     mov rcx, 0
     mov rdx, 1
     pop rbx
     pop rax
     cmp rax, rbx
-    cmovge rcx, rdx
+    cmov%1 rcx, rdx
     push rcx
 %endmacro
 
@@ -250,10 +274,11 @@ const MACRO_DEFINITIONS: &'static str = "\
     push rbx
 %endmacro
 
-%macro OP_Shr 0
+; shr or sar
+%macro OP_Shr 1
     pop rcx
     pop rbx
-    shr rbx, cl
+    %1 rbx, cl
     push rbx
 %endmacro
 
@@ -273,6 +298,21 @@ const MACRO_DEFINITIONS: &'static str = "\
     push rdx
 %endmacro
 
+%macro OP_IDivMod 0
+    pop rbx
+    pop rax
+    cqo
+    idiv rbx
+    push rax
+    push rdx
+%endmacro
+
+%macro OP_Truncate 1+
+    pop rax
+    %1
+    push rax
+%endmacro
+
 %macro OP_Mul 0
     pop rax
     pop rbx
@@ -347,16 +387,70 @@ const MACRO_DEFINITIONS: &'static str = "\
     push mem_%1
 %endmacro
 
+; offset from the end of the current call frame
+%macro OP_LocalMemUse 1
+    lea rax, [rbp - %1]
+    push rax
+%endmacro
+
 %macro OP_FnCall 1
     call %1
 %endmacro
 
+%macro OP_FnAddr 1
+    lea rax, [rel %1]
+    push rax
+%endmacro
+
+; the callee takes its return address from the stack like with a direct call
+%macro OP_Call 0
+    pop rax
+    call rax
+%endmacro
+
+; leave the current frame and let the callee return to our caller
+%macro OP_TailCall 1
+    sub rbp, 8
+    mov rbx, qword [rbp]
+    push rbx
+    jmp %1
+%endmacro
+
 %macro OP_ConstUse 1
     mov rax, qword [const_%1]
     push rax
 %endmacro
 
-%macro OP_StructUse 1
-    push struct_%1
+%macro OP_CovCount 1
+    inc qword [cov_counters + %1 * 8]
+%endmacro
+
+; dumps the counters if the syscall number on top of the stack ends the program
+%macro OP_CovExit 0
+    mov rax, [rsp]
+    cmp rax, 60 ; exit
+    je %%dump
+    cmp rax, 231 ; exit_group
+    jne %%done
+%%dump:
+    call cov_dump
+%%done:
 %endmacro
+
+; counters_size
+%macro OP_CovDump 1
+    mov rax, 2 ; open
+    lea rdi, [rel cov_file]
+    mov rsi, 1089 ; O_WRONLY | O_CREAT | O_APPEND
+    mov rdx, 420 ; 0o644
+    syscall
+    mov rdi, rax
+    mov rax, 1 ; write
+    lea rsi, [rel cov_counters]
+    mov rdx, %1
+    syscall
+    mov rax, 3 ; close
+    syscall
+%endmacro
+
 ";
\ No newline at end of file
@@ -4,6 +4,18 @@
 pub const DEV_MODE: bool = true;
 
 pub const DEFAULT_OUT_FILE: &str = "a.out";
+
+/**
+ * Bytes the interpreter has for memories and for string literals
+ */
+pub const MEM_SZ: usize = 640 * 1000;
+pub const STRING_SZ: usize = 10 * 1000;
+
+/**
+ * Bytes reserved for return addresses and the local memories of every active call
+ */
+pub const RET_STACK_SZ: usize = 1024 * 1024;
+
 pub const DEFAULT_INCLUDES: [&str;2] = [
     "./include",
     "~/.mclang/include",
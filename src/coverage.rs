@@ -0,0 +1,167 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, bail};
+use clap::Parser;
+
+use crate::definitions::{KeywordType, Operator, OpType};
+use crate::{error, info};
+
+/// One instrumented block, the op it starts at and the function its in
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub ip: usize,
+    pub function: Option<String>,
+}
+
+/// Finds the start of every basic block, these are function entries, the jump targets
+/// of 'if', 'else', 'elif', 'do', 'break', 'continue' and 'end', the bodies of 'if' and 'do'
+/// and the arms of a 'match'
+pub fn blocks(ops: &[Operator]) -> Vec<Block> {
+    let mut starts: Vec<usize> = Vec::new();
+    for (ip, op) in ops.iter().enumerate() {
+        match op.typ {
+            OpType::Keyword(KeywordType::FunctionDef | KeywordType::FunctionDefExported | KeywordType::While) => starts.push(ip),
+            OpType::Keyword(KeywordType::If | KeywordType::Do) => {
+                starts.push(ip + 1);
+                starts.push(op.jmp);
+            }
+            OpType::Keyword(KeywordType::Else | KeywordType::Elif | KeywordType::End | KeywordType::Break | KeywordType::Continue) => starts.push(op.jmp),
+            OpType::Keyword(KeywordType::Case | KeywordType::Default) => starts.push(ip),
+            _ => ()
+        }
+    }
+    starts.sort_unstable();
+    starts.dedup();
+
+    let mut blocks = Vec::new();
+    let mut function = None;
+    let mut start = starts.iter().peekable();
+    for (ip, op) in ops.iter().enumerate() {
+        if let OpType::Keyword(KeywordType::FunctionDef | KeywordType::FunctionDefExported) = op.typ {
+            function = Some(op.text.clone());
+        }
+        if start.next_if_eq(&&ip).is_some() {
+            blocks.push(Block { ip, function: function.clone() });
+        }
+        if let OpType::Keyword(KeywordType::FunctionDone) = op.typ {
+            function = None;
+        }
+    }
+    blocks
+}
+
+/// Writes the counter index to source location table next to the executable,
+/// one line per counter: `fn` or `bb`, file, line and function name separated by tabs
+pub fn write_map(path: &Path, ops: &[Operator], blocks: &[Block]) -> Result<()> {
+    let mut map = String::new();
+    for b in blocks {
+        let op = &ops[b.ip];
+        let kind = match op.typ {
+            OpType::Keyword(KeywordType::FunctionDef | KeywordType::FunctionDefExported) => "fn",
+            _ => "bb"
+        };
+        writeln!(map, "{kind}\t{}\t{}\t{}", op.loc.0, op.loc.1, b.function.clone().unwrap_or_default())?;
+    }
+    fs::write(path, map)?;
+    Ok(())
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(name="mclangc cov", about="Merge coverage dumps of a program compiled with --coverage into lcov.info")]
+pub struct CovArgs {
+    /// The executable that was compiled with --coverage
+    bin: String,
+
+    /// Output lcov file
+    #[arg(long, short, default_value_t=String::from("lcov.info"))]
+    out_file: String,
+}
+
+/// line => count, function => (line, count)
+type FileCoverage<'a> = (BTreeMap<usize, u64>, HashMap<&'a str, (usize, u64)>);
+
+struct MapEntry {
+    kind: String,
+    file: String,
+    line: usize,
+    function: String,
+}
+
+pub fn cov(args: &CovArgs) -> Result<()> {
+    let map_path = PathBuf::from(&args.bin).with_extension("covmap");
+    let dump_path = PathBuf::from(&args.bin).with_extension("cov");
+
+    let Ok(map) = fs::read_to_string(&map_path) else {
+        error!("Failed to read coverage map {}, was the program compiled with --coverage?", map_path.display());
+        bail!("");
+    };
+
+    let mut entries = Vec::new();
+    for line in map.lines() {
+        let parts: Vec<&str> = line.split('\t').collect();
+        let [kind, file, ln, function] = parts[..] else {
+            error!("Malformed coverage map line {line:?}");
+            bail!("");
+        };
+        entries.push(MapEntry {
+            kind: kind.to_string(),
+            file: file.to_string(),
+            line: ln.parse()?,
+            function: function.to_string(),
+        });
+    }
+
+    // every run appends one record of all the counters
+    let mut counts = vec![0u64; entries.len()];
+    let dump = fs::read(&dump_path).unwrap_or_default();
+    let record = entries.len() * 8;
+    if record != 0 {
+        if dump.len() % record != 0 {
+            error!("Coverage dump {} does not match the coverage map, recompile and rerun the program", dump_path.display());
+            bail!("");
+        }
+        for run in dump.chunks(record) {
+            for (i, c) in run.chunks(8).enumerate() {
+                counts[i] += u64::from_le_bytes(c.try_into()?);
+            }
+        }
+        info!("Merged {} runs", dump.len() / record);
+    }
+
+    let mut files: BTreeMap<&str, FileCoverage> = BTreeMap::new();
+    for (e, count) in entries.iter().zip(counts) {
+        let (lines, functions) = files.entry(&e.file).or_default();
+        *lines.entry(e.line).or_default() += count;
+        if e.kind == "fn" {
+            functions.insert(&e.function, (e.line, count));
+        }
+    }
+
+    let mut lcov = String::from("TN:\n");
+    for (file, (lines, functions)) in files {
+        writeln!(lcov, "SF:{file}")?;
+        let mut functions: Vec<_> = functions.into_iter().collect();
+        functions.sort_by_key(|f| f.1.0);
+        for (name, (line, _)) in &functions {
+            writeln!(lcov, "FN:{line},{name}")?;
+        }
+        for (name, (_, count)) in &functions {
+            writeln!(lcov, "FNDA:{count},{name}")?;
+        }
+        writeln!(lcov, "FNF:{}", functions.len())?;
+        writeln!(lcov, "FNH:{}", functions.iter().filter(|f| f.1.1 > 0).count())?;
+        for (line, count) in &lines {
+            writeln!(lcov, "DA:{line},{count}")?;
+        }
+        writeln!(lcov, "LF:{}", lines.len())?;
+        writeln!(lcov, "LH:{}", lines.values().filter(|c| **c > 0).count())?;
+        writeln!(lcov, "end_of_record")?;
+    }
+
+    fs::write(&args.out_file, lcov)?;
+    info!("Wrote {}", args.out_file);
+    Ok(())
+}
@@ -2,6 +2,8 @@ use std::collections::HashMap;
 
 use anyhow::{Result, bail};
 
+use crate::{lnote, note};
+
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum InstructionType {
@@ -41,6 +43,9 @@ pub enum InstructionType {
     Write32,
     Read64,
     Write64,
+    Index,
+    IndexRead,
+    IndexWrite,
 
     // syscalls
     Syscall0,
@@ -54,24 +59,41 @@ pub enum InstructionType {
     CastBool,
     CastPtr,
     CastInt,
+    CastSized,
     CastVoid,
+    CastEnum,
+    CastStruct,
+    CastFn,
+    CastArray,
 
     // typing
     TypeBool,
     TypePtr,
     TypeInt,
+    TypeSized,
     TypeVoid,
     // TypeStr,
     TypeAny,
+    TypeEnum,
+    TypeStruct,
+    TypeFn,
+    TypeArray,
+    TypeVar,
     Returns,
     With,
 
     FnCall,
+    FnAddr,
+    Call,
     MemUse,
+    LocalMemUse,
     ConstUse,
-    StructUse,
+    Field,
+    FieldRead,
+    FieldWrite,
 
     Return,
+    InlineAsm,
     None // Used for macros and any other non built in word definitions
 
 }
@@ -79,9 +101,15 @@ pub enum InstructionType {
 pub enum KeywordType {
     If,
     Else,
+    Elif,
     End,
     While,
     Do,
+    Break,
+    Continue,
+    Match,
+    Case,
+    Default,
     Include,
     Memory,
     Constant,
@@ -94,14 +122,21 @@ pub enum KeywordType {
     Inline,
     Export,
     Struct,
+    Asm,
+    Macro,
+    CondIf,
+    CondThen,
+    CondElse,
+    CondEnd,
+    Priv,
+    Enum,
+    Alloc,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum InternalType {
     Arrow,
-    StructAlloc {
-        name: String
-    }
+    DocComment,
 }
 #[derive(Debug, Clone, PartialEq)]
 pub enum OpType {
@@ -119,7 +154,8 @@ pub struct Operator{
     pub addr: Option<usize>, //? only used for OpType::PushStr
     pub jmp: usize,
     pub loc: Loc,
-    pub types: (usize, usize)
+    pub types: (usize, usize),
+    pub expansion: Vec<(String, Loc)> //* macros this op was expanded from and where, innermost first
 }
 
 impl Operator {
@@ -132,7 +168,27 @@ impl Operator {
             text,
             loc: (file, row, col),
             tok_typ,
-            types: (0, 0)
+            types: (0, 0),
+            expansion: Vec::new()
+        }
+    }
+
+    /// Notes where the macros this op came from were used, call after reporting an error on it,
+    /// of deep expansions only the innermost and outermost few
+    pub fn note_expansion(&self) {
+        const KEEP: usize = 3;
+        // hiding a single note would not make it shorter
+        let hidden = match self.expansion.len().saturating_sub(KEEP * 2) {
+            0 | 1 => 0..0,
+            n => KEEP..KEEP + n
+        };
+        for (i, (name, loc)) in self.expansion.iter().enumerate() {
+            if i == hidden.start && !hidden.is_empty() {
+                note!("... {} more expansions", hidden.len());
+            }
+            if !hidden.contains(&i) {
+                lnote!(loc, "in expansion of macro '{name}'");
+            }
         }
     }
     pub fn set_addr(&mut self, addr: usize) -> Self {
@@ -182,6 +238,9 @@ impl OpType {
                     InstructionType::Write32 => "write32",
                     InstructionType::Read64 => "read64",
                     InstructionType::Write64 => "write64",
+                    InstructionType::Index => "[]",
+                    InstructionType::IndexRead => "[]@",
+                    InstructionType::IndexWrite => "[]!",
                     InstructionType::Syscall0 => "syscall0",
                     InstructionType::Syscall1 => "syscall1",
                     InstructionType::Syscall2 => "syscall2",
@@ -192,29 +251,52 @@ impl OpType {
                     InstructionType::CastBool => "cast(bool",
                     InstructionType::CastPtr => "cast(ptr)",
                     InstructionType::CastInt => "cast(int)",
+                    InstructionType::CastSized => "cast(sized int)",
                     InstructionType::CastVoid => "cast(void)",
+                    InstructionType::CastEnum => "cast(enum)",
+                    InstructionType::CastStruct => "cast(struct)",
+                    InstructionType::CastFn => "cast(fn)",
+                    InstructionType::CastArray => "cast(array)",
                     InstructionType::None => "None",
                     InstructionType::MemUse => "Memory use (internal)",
+                    InstructionType::LocalMemUse => "Local memory use (internal)",
                     InstructionType::FnCall => "Function Call (Internal)",
+                    InstructionType::FnAddr => "Function address (internal)",
+                    InstructionType::Call => "call",
                     InstructionType::ConstUse => "Constant Use (Internal)",
-                    InstructionType::StructUse => "Struct Use (Internal)",
+                    InstructionType::Field => "Struct field (internal)",
+                    InstructionType::FieldRead => "@",
+                    InstructionType::FieldWrite => "!",
                     InstructionType::Return => "return",
+                    InstructionType::InlineAsm => "inline asm (internal)",
                     InstructionType::TypeBool => "bool",
                     InstructionType::TypePtr => "ptr",
                     InstructionType::TypeInt => "int",
+                    InstructionType::TypeSized => "sized int",
                     InstructionType::TypeVoid => "void",
                     InstructionType::Returns => "returns",
                     InstructionType::With => "with",
                     InstructionType::TypeAny => "any",
+                    InstructionType::TypeEnum => "enum type",
+                    InstructionType::TypeStruct => "struct type",
+                    InstructionType::TypeFn => "fn type",
+                    InstructionType::TypeArray => "array type",
+                    InstructionType::TypeVar => "type variable",
                 }
             }
             OpType::Keyword(keyword) => {
                 match keyword {
                     KeywordType::If => "if",
                     KeywordType::Else => "else",
+                    KeywordType::Elif => "elif",
                     KeywordType::End => "end",
                     KeywordType::While => "while",
                     KeywordType::Do => "do",
+                    KeywordType::Break => "break",
+                    KeywordType::Continue => "continue",
+                    KeywordType::Match => "match",
+                    KeywordType::Case => "case",
+                    KeywordType::Default => "default",
                     KeywordType::Include => "include",
                     KeywordType::Memory => "memory",
                     KeywordType::Function => "fn",
@@ -227,12 +309,21 @@ impl OpType {
                     KeywordType::Inline => "inline",
                     KeywordType::Export => "export",
                     KeywordType::Struct => "struct",
+                    KeywordType::Asm => "asm",
+                    KeywordType::Macro => "macro",
+                    KeywordType::CondIf => "#if",
+                    KeywordType::CondThen => "#then",
+                    KeywordType::CondElse => "#else",
+                    KeywordType::CondEnd => "#end",
+                    KeywordType::Priv => "priv",
+                    KeywordType::Enum => "enum",
+                    KeywordType::Alloc => "alloc",
                 }
             }
             OpType::Internal(t) => {
                 match t {
                     InternalType::Arrow => "->",
-                    InternalType::StructAlloc{..} => "Struct alloc ( internal )",
+                    InternalType::DocComment => "///",
                 }
             },
             
@@ -242,9 +333,7 @@ impl OpType {
 
 #[derive(Debug, Clone)]
 pub struct Token {
-    pub file: String,
-    pub line: usize,
-    pub col: usize,
+    pub span: crate::types::common::Loc,
     pub text: String,
     pub typ: TokenType,
     pub value: Option<usize>, //* only used for Memories
@@ -258,16 +347,14 @@ pub enum TokenType {
     Int,
     String,
     CString,
-    Char
+    Char,
+    Asm,
+    DocComment
 }
 
 impl Token {
     pub fn loc(&self) -> Loc {
-        (
-            self.file.clone(),
-            self.line,
-            self.col
-        )
+        self.span.as_tuple()
     }
 }
 
@@ -278,7 +365,9 @@ impl TokenType {
             TokenType::Int => "Int",
             TokenType::String => "String",
             TokenType::CString => "CString",
-            TokenType::Char => "Char"
+            TokenType::Char => "Char",
+            TokenType::Asm => "Asm",
+            TokenType::DocComment => "DocComment"
         }.to_string()
     }
 }
@@ -299,6 +388,22 @@ pub enum Types {
     I16,
     I32,
     I64,
+    /// an enum, by its internal name
+    Enum(String),
+    /// pointer to an instance of a struct, by its internal name, a struct field of this type is
+    /// the instance itself
+    Struct(String),
+    /// pointer to a function, with its arguments and return types
+    Fn(Vec<Types>, Vec<Types>),
+    /// pointer to values of a type, with how many there are if that is known, arrays and struct
+    /// fields are these
+    PtrTo(Box<Types>, Option<usize>),
+    /// a type variable of a signature, by its name without the `'`
+    Var(String),
+    /// an int without a width of its own, a literal or what is computed from literals only. It
+    /// can be used as any int its value fits in, once the value is not known anymore as any int
+    /// that is signed like it
+    Lit { value: Option<i128>, signed: bool },
     
     #[allow(dead_code)] //TODO: Implement custom types
     Custom{
@@ -307,9 +412,41 @@ pub enum Types {
     // todo: add signed numbers since we dont have them yet lol
 }
 
+/// How types are written in a program, `int` for a u64 and definitions by the names they are used with
+impl std::fmt::Display for Types {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let list = |ts: &[Types]| if ts.is_empty() {
+            "void".to_string()
+        } else {
+            ts.iter().map(ToString::to_string).collect::<Vec<_>>().join(" ")
+        };
+        match self {
+            Types::U64 => write!(f, "int"),
+            Types::Enum(name) | Types::Struct(name) => write!(f, "{}", display_name(name)),
+            Types::Fn(args, returns) => write!(f, "fn({} -> {})", list(args), list(returns)),
+            Types::PtrTo(t, count) => write!(f, "{t}[{}]", count.map_or(String::new(), |c| c.to_string())),
+            Types::Var(name) => write!(f, "'{name}"),
+            Types::Lit { value: Some(v), .. } => write!(f, "int literal {v}"),
+            Types::Lit { value: None, signed: true } => write!(f, "signed int literal"),
+            Types::Lit { value: None, signed: false } => write!(f, "int literal"),
+            Types::Custom { size } => write!(f, "custom({size})"),
+            t => write!(f, "{}", format!("{t:?}").to_lowercase())
+        }
+    }
+}
+
+/// The name a definition is used with, for its internal name
+fn display_name(name: &str) -> String {
+    // private ones are `__PRIV<file id>__<name>`
+    let name = name.strip_prefix("__PRIV")
+        .and_then(|n| n.split_once("__"))
+        .map_or(name, |(_, n)| n);
+    name.replace("__NS__", "::").replace("__OP_PAREN__", "(").replace("__CL_PAREN__", ")")
+}
+
 impl Types {
     pub fn get_size(&self) -> u64 {
-        match *self {
+        match self {
             Types::Any => 0, // any cant be a known size
             Types::Void => 0,
             Types::Bool => 1,
@@ -321,8 +458,14 @@ impl Types {
             Types::I32 => 4,
             Types::Ptr |
             Types::U64 |
-            Types::I64 => 8,
-            Types::Custom { size } => size,
+            Types::I64 |
+            Types::Enum(_) |
+            Types::Struct(_) |
+            Types::Fn(..) |
+            Types::PtrTo(..) |
+            Types::Var(_) |
+            Types::Lit { .. } => 8,
+            Types::Custom { size } => *size,
         }
     }
     pub fn from_string<S: Into<String> + std::fmt::Display>(s: &S) -> Result<Self> {
@@ -342,6 +485,89 @@ impl Types {
             _ => bail!("Unknown type {s}")
         }
     }
+
+    /// Element types of arrays are stored by name, enums by their internal name and typed
+    /// pointers as `u32[8]`, or `u32[]` without a length
+    pub fn from_element(s: &str) -> Self {
+        if let Some((elem, count)) = s.strip_suffix(']').and_then(|s| s.rsplit_once('[')) {
+            return Types::PtrTo(Box::new(Self::from_element(elem)), count.parse().ok());
+        }
+        Self::from_string(&s).unwrap_or_else(|_| Types::Enum(s.to_string()))
+    }
+
+    /// The name `from_element` reads back
+    pub fn element_name(&self) -> String {
+        match self {
+            Types::Enum(name) | Types::Struct(name) => name.clone(),
+            Types::PtrTo(t, count) => format!("{}[{}]", t.element_name(), count.map_or(String::new(), |c| c.to_string())),
+            t => format!("{t:?}").to_lowercase()
+        }
+    }
+
+    /// Name of this type inside of a function pointer type, structs are marked since their names
+    /// would be read back as enums
+    pub fn signature_name(&self) -> String {
+        match self {
+            Types::Struct(name) => format!("struct:{name}"),
+            Types::Var(name) => format!("'{name}"),
+            t => t.element_name()
+        }
+    }
+
+    /// Reads a function pointer type back from `<args> -> <returns>`, by their `signature_name`
+    pub fn from_signature(s: &str) -> Self {
+        let (args, returns) = s.split_once("->").unwrap_or((s, ""));
+        let read = |s: &str| s.split_whitespace().map(|t| if let Some(name) = t.strip_prefix("struct:") {
+            Types::Struct(name.to_string())
+        } else if let Some(name) = t.strip_prefix('\'') {
+            Types::Var(name.to_string())
+        } else {
+            Types::from_element(t)
+        }).collect();
+        Types::Fn(read(args), read(returns))
+    }
+
+    pub fn is_int(&self) -> bool {
+        matches!(self, Types::U8 | Types::U16 | Types::U32 | Types::U64 | Types::I8 | Types::I16 | Types::I32 | Types::I64 | Types::Lit { .. })
+    }
+
+    pub fn is_signed(&self) -> bool {
+        matches!(self, Types::I8 | Types::I16 | Types::I32 | Types::I64 | Types::Lit { signed: true, .. })
+    }
+
+    /// The values an int of this size can hold
+    fn int_range(&self) -> std::ops::RangeInclusive<i128> {
+        let bits = self.get_size() * 8;
+        if self.is_signed() {
+            -(1 << (bits - 1))..=(1 << (bits - 1)) - 1
+        } else {
+            0..=(1 << bits) - 1
+        }
+    }
+
+    /// If a value of this type can be used as a `t` without a cast, ints only get wider and
+    /// signed ones never become unsigned, typed pointers can forget their length or their type
+    pub fn widens_to(&self, t: &Types) -> bool {
+        if self == t {
+            return true;
+        }
+        match (self, t) {
+            (Types::PtrTo(..), Types::Ptr) => return true,
+            (Types::PtrTo(a, _), Types::PtrTo(b, None)) => return a == b,
+            (Types::Lit { .. }, Types::Lit { .. }) => return true,
+            (Types::Lit { value: Some(v), .. }, t) => return t.is_int() && t.int_range().contains(v),
+            (Types::Lit { value: None, signed }, t) => return t.is_int() && (!signed || t.is_signed()),
+            _ => ()
+        }
+        if !self.is_int() || !t.is_int() {
+            return false;
+        }
+        match (self.is_signed(), t.is_signed()) {
+            (false, true) => self.get_size() < t.get_size(),
+            (true, false) => false,
+            _ => self.get_size() <= t.get_size()
+        }
+    }
 }
 
 
@@ -353,40 +579,82 @@ pub struct Function {
     pub loc: Loc,
     pub name: String,
     pub inline: bool,
-    pub tokens: Option<Vec<Operator>>
+    pub tokens: Option<Vec<Operator>>,
+    pub memories: Memories, //* function local memories, `id` is the offset in the call frame
+    pub frame_size: usize,
+    pub doc: Option<String>
 }
 
 #[derive(Debug, Clone)]
 pub struct Constant {
     pub loc: Loc,
-    pub name: String
+    pub name: String,
+    pub value: ConstValue,
+    pub doc: Option<String>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConstValue {
+    Int(usize),
+    Str(String),
+    CStr(String), //* includes the trailing '\0'
 }
 
 #[derive(Debug, Clone)]
 pub struct Memory {
     pub loc: Loc,
-    pub id: usize
-    
+    pub id: usize,
+    pub doc: Option<String>,
+    //* element type and count of an array, see `Types::from_element`
+    pub array: Option<(String, usize)>,
+    //* internal name of the struct this is an instance of
+    pub instance: Option<String>
+}
+
+#[derive(Debug, Clone)]
+pub struct Macro {
+    pub loc: Loc,
+    pub name: String,
+    pub tokens: Vec<Operator>
+}
+
+#[derive(Debug, Clone)]
+pub struct Enum {
+    pub loc: Loc,
+    pub name: String,
+    pub doc: Option<String>
 }
 
 #[derive(Debug, Clone)]
 pub struct StructDef {
     pub loc: Loc,
     pub name: String,
-    pub fields: Vec<(String, Types)>
+    pub fields: Vec<StructField>,
+    pub size: usize,
+    pub align: usize
+}
+
+#[derive(Debug, Clone)]
+pub struct StructField {
+    pub name: String,
+    pub typ: Types,
+    pub offset: usize
 }
 
 pub type Functions = HashMap<String, Function>;
 pub type Memories = HashMap<String, Memory>;
 pub type Constants = HashMap<String, Constant>;
 pub type StructDefs = HashMap<String, StructDef>;
+pub type Macros = HashMap<String, Macro>;
+pub type Enums = HashMap<String, Enum>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Program {
     pub ops: Vec<Operator>,
     pub functions: Functions,
     pub memories: Memories,
     pub constants: Constants,
     pub struct_defs: StructDefs,
-    pub struct_allocs: HashMap<String, String>
+    pub macros: Macros,
+    pub enums: Enums,
 }
@@ -0,0 +1,133 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use clap::ValueEnum;
+
+use crate::definitions::{InstructionType, KeywordType, Loc, Operator, OpType, Program, Token};
+use crate::{info, Args};
+
+/// Pipeline stages that can be written out, in the order they run
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Emit {
+    /// Lexer tokens
+    Tokens,
+    /// Operators before preprocessing
+    Ops,
+    /// Operators after preprocessing and cross referencing
+    Expanded,
+    /// Doc comments of functions, constants and memories
+    Docs,
+    /// Nasm assembly
+    Asm,
+    /// Object file, not linked
+    Obj,
+    /// Linked executable
+    Exe,
+}
+
+impl Emit {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Emit::Tokens => "tokens",
+            Emit::Ops => "ops",
+            Emit::Expanded => "expanded",
+            Emit::Docs => "docs",
+            Emit::Asm => "nasm",
+            Emit::Obj => "o",
+            Emit::Exe => "",
+        }
+    }
+
+    /// Where this stage gets written to, next to the output file
+    pub fn path(self, args: &Args) -> PathBuf {
+        PathBuf::from(&args.out_file).with_extension(self.extension())
+    }
+}
+
+pub fn tokens(path: &Path, tokens: &[Token], quiet: bool) -> Result<()> {
+    let mut out = String::new();
+    for t in tokens {
+        writeln!(out, "{}\t{}:{}..{}\t{}\t{:?}", t.span, t.span.file_id, t.span.start, t.span.end, t.typ.human(), t.text)?;
+    }
+    write(path, &out, quiet)
+}
+
+pub fn ops(path: &Path, ops: &[Operator], quiet: bool) -> Result<()> {
+    let mut out = String::new();
+    for (ip, op) in ops.iter().enumerate() {
+        let arg = match &op.typ {
+            OpType::Instruction(InstructionType::PushInt) => op.value.to_string(),
+            OpType::Instruction(InstructionType::PushStr | InstructionType::PushCStr | InstructionType::InlineAsm) => format!("{:?}", op.text),
+            OpType::Instruction(InstructionType::MemUse | InstructionType::LocalMemUse) => format!("{} ({})", op.text, op.addr.unwrap_or(0)),
+            OpType::Keyword(KeywordType::Memory) => format!("{} bytes", op.value),
+            OpType::Keyword(KeywordType::Case) => op.value.to_string(),
+            OpType::Instruction(InstructionType::Field) => format!("{} +{}", op.text, op.value),
+            OpType::Instruction(InstructionType::None) |
+            OpType::Instruction(InstructionType::FnCall | InstructionType::FnAddr | InstructionType::ConstUse | InstructionType::TypeEnum |
+                InstructionType::CastEnum | InstructionType::TypeStruct | InstructionType::CastStruct | InstructionType::TypeFn | InstructionType::CastFn | InstructionType::TypeVar |
+                InstructionType::TypeArray | InstructionType::CastArray) |
+            OpType::Keyword(KeywordType::FunctionDef | KeywordType::FunctionDefExported | KeywordType::ConstantDef) => op.text.clone(),
+            _ => String::new()
+        };
+        let jmp = match op.typ {
+            OpType::Keyword(KeywordType::If | KeywordType::Else | KeywordType::Elif | KeywordType::End | KeywordType::Do | KeywordType::Break | KeywordType::Continue |
+                KeywordType::Match | KeywordType::Case | KeywordType::Default) |
+            OpType::Instruction(InstructionType::InlineAsm) => format!("-> {}", op.jmp),
+            _ => String::new()
+        };
+        writeln!(out, "{ip:>6}  {:<32} {arg:<24} {jmp:<8} {}:{}:{}", op.typ.human(), op.loc.0, op.loc.1, op.loc.2)?;
+    }
+    write(path, &out, quiet)
+}
+
+pub fn docs(path: &Path, program: &Program, quiet: bool) -> Result<()> {
+    let mut defs: Vec<(&Loc, &str, &str, &str)> = Vec::new();
+    for (name, f) in &program.functions {
+        if let Some(doc) = &f.doc {
+            defs.push((&f.loc, "fn", name, doc));
+        }
+        for (mname, m) in &f.memories {
+            if let Some(doc) = &m.doc {
+                defs.push((&m.loc, "memory", mname, doc));
+            }
+        }
+    }
+    for (name, c) in &program.constants {
+        if let Some(doc) = &c.doc {
+            defs.push((&c.loc, "const", name, doc));
+        }
+    }
+    for (name, m) in &program.memories {
+        if let Some(doc) = &m.doc {
+            defs.push((&m.loc, "memory", name, doc));
+        }
+    }
+    for (name, e) in &program.enums {
+        if let Some(doc) = &e.doc {
+            defs.push((&e.loc, "enum", name, doc));
+        }
+    }
+    // 'priv' definitions of included files are not part of what they offer
+    defs.retain(|d| !d.2.starts_with("__PRIV"));
+    defs.sort_by(|a, b| (&a.0.0, a.0.1).cmp(&(&b.0.0, b.0.1)));
+
+    let mut out = String::new();
+    for (loc, kind, name, doc) in defs {
+        writeln!(out, "{kind} {name}\t{}:{}:{}", loc.0, loc.1, loc.2)?;
+        for line in doc.lines() {
+            writeln!(out, "    {line}")?;
+        }
+        writeln!(out)?;
+    }
+    write(path, &out, quiet)
+}
+
+fn write(path: &Path, out: &str, quiet: bool) -> Result<()> {
+    fs::write(path, out)?;
+    if !quiet {
+        info!("Wrote {}", path.display());
+    }
+    Ok(())
+}
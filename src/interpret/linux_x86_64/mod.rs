@@ -1,42 +1,43 @@
 use std::collections::HashMap;
 
-use crate::{constants::{OpType, Loc, InstructionType, KeywordType, Operator}, lerror, error};
-// use crate::util::logger;
-use color_eyre::Result;
-use eyre::eyre;
+use crate::{definitions::{OpType, Loc, InstructionType, KeywordType, Operator, Program, ConstValue, Types}, config::{MEM_SZ, STRING_SZ, RET_STACK_SZ}, lerror};
+use anyhow::{Result, bail};
 
-use super::{Memory, Function, Constant};
 mod syscalls;
 
 fn stack_pop(stack: &mut Vec<usize>, pos: &Loc) -> Result<usize> {
     if let Some(i) = stack.pop() { Ok(i) } else {
         lerror!(&pos.clone(), "Stack underflow");
-        Err(eyre!("Stack underflow"))
+        bail!("")
     }
 }
 
-pub fn run(ops: &[crate::constants::Operator]) -> Result<i32>{
+/// Runs the program, memories come first in `mem`, then the strings, then the call frames
+pub fn run(program: &Program) -> Result<i32>{
+    let ops = &program.ops;
     let mut stack: Vec<usize> = Vec::new();
-    let mut mem: Vec<u64> = vec![0; crate::MEM_SZ + crate::STRING_SZ];
+    let mut mem: Vec<u8> = vec![0; MEM_SZ + STRING_SZ + RET_STACK_SZ];
+    // strings are only written once, the same text gets the same address
+    let mut strings: HashMap<&str, usize> = HashMap::new();
     let mut string_idx = 0;
-    
-    let prerunned = pre_run(ops);
+    // const ops use the constants name, which is not its key if it came from a namespaced include
+    let const_values: HashMap<&str, &ConstValue> = program.constants.values().map(|c| (c.name.as_str(), &c.value)).collect();
+
+    let prerunned = pre_run(ops)?;
     let functions = prerunned.functions;
-    let constants = prerunned.constants;
     let memories = prerunned.memories;
 
     let mut ret_stack: Vec<usize> = Vec::new();
-    
-    // for token in &tokens {
-    //     println!("{{typ: \"{:?}\", val: {}, jmp: {}}}", token.typ, token.value, token.jmp);
-    // }
-        
-    // jump to main func    
-    let mut ip = if let Some(i) = functions.get("main") {i.id} else {
+    // where the local memories of every active call start, and where the next frame goes
+    let mut frames: Vec<usize> = Vec::new();
+    let mut frame_top = MEM_SZ + STRING_SZ;
+
+    // jump to main func
+    let mut ip = if let Some(i) = functions.get("main") {*i} else {
         crate::errors::missing_main_fn();
-        return Err(eyre!(""));
+        bail!("");
     };
-    
+
     while ip < ops.len() {
         let op = &ops[ip];
         let pos = op.loc.clone();
@@ -48,39 +49,14 @@ pub fn run(ops: &[crate::constants::Operator]) -> Result<i32>{
                         ip += 1;
                     },
                     InstructionType::PushStr => {
-                        if op.addr.is_none() {
-                            stack.push(op.text.len()); // string len
-                            stack.push(string_idx + crate::MEM_SZ);
-                            
-                            for c in op.text.bytes() {
-                                mem[crate::MEM_SZ + string_idx] = u64::from(c);
-                                string_idx += 1;
-                            }
-                        } else {
-                            stack.push(op.text.len()); 
-                            if let Some(addr) = op.addr {
-                                stack.push(addr);
-                            }
-                        }
-        
-        
+                        let addr = intern(&mut mem, &mut strings, &mut string_idx, &op.text, &pos)?;
+                        stack.push(op.text.len()); // string len
+                        stack.push(addr);
                         ip += 1;
                     },
                     InstructionType::PushCStr => {
-                        if op.addr.is_none() {
-                            stack.push(string_idx + crate::MEM_SZ);
-                            
-                            for c in op.text.bytes() {
-                                mem[crate::MEM_SZ + string_idx] = u64::from(c);
-                                string_idx += 1;
-                            }
-                        } else {
-                            if let Some(addr) = op.addr {
-                                stack.push(addr);
-                            }
-                        }
-        
-        
+                        let addr = intern(&mut mem, &mut strings, &mut string_idx, &op.text, &pos)?;
+                        stack.push(addr);
                         ip += 1;
                     },
                     InstructionType::Drop => {
@@ -93,7 +69,7 @@ pub fn run(ops: &[crate::constants::Operator]) -> Result<i32>{
                         stack.push(a);
                         ip += 1;
                     },
-        
+
                     InstructionType::Rot => {
                         let a = stack_pop(&mut stack, &pos)?;
                         let b = stack_pop(&mut stack, &pos)?;
@@ -118,78 +94,107 @@ pub fn run(ops: &[crate::constants::Operator]) -> Result<i32>{
                         stack.push(b);
                         ip += 1;
                     }
-        
+
+                    #[allow(clippy::cast_possible_wrap)]
                     InstructionType::Print => {
                         let a = stack_pop(&mut stack, &pos)?;
-                        println!("{a}");
-                        // let _ = io::stdout().flush();
+                        //? the typechecker sets value to 1 for these if they work on signed ints
+                        if op.value == 1 {
+                            println!("{}", a as i64);
+                        } else {
+                            println!("{a}");
+                        }
                         ip += 1;
                     },
-                    #[allow(clippy::cast_possible_truncation)]
-                    InstructionType::Read8 |
-                    InstructionType::Read32 |
+                    InstructionType::Read8 => {
+                        let a = stack_pop(&mut stack, &pos)?;
+                        stack.push(load(&mem, a, 1, false, &pos)?);
+                        ip += 1;
+                    }
+                    InstructionType::Read32 => {
+                        let a = stack_pop(&mut stack, &pos)?;
+                        stack.push(load(&mem, a, 4, false, &pos)?);
+                        ip += 1;
+                    }
                     InstructionType::Read64 => {
                         let a = stack_pop(&mut stack, &pos)?;
-                        if a > crate::MEM_SZ + crate::STRING_SZ {
-                            lerror!(&op.loc, "Invalid memory address {a}");
-                            return Ok(1);
-                        }
-                        let byte = mem[a];
-                        stack.push(byte as usize);
+                        stack.push(load(&mem, a, 8, false, &pos)?);
                         ip += 1;
                     }
-                    #[allow(clippy::cast_possible_truncation)]
                     InstructionType::Write8 => {
                         let val = stack_pop(&mut stack, &pos)?;
                         let addr = stack_pop(&mut stack, &pos)?;
-                        
-                        if addr > crate::MEM_SZ {
-                            lerror!(&op.loc, "Invalid memory address {addr}");
-                            return Ok(1);
-                        }
-
-                        mem[addr] = u64::from(val as u8);
+                        store(&mut mem, addr, 1, val, &pos)?;
                         ip += 1;
                     }
-                    #[allow(clippy::cast_possible_truncation)]
                     InstructionType::Write32 => {
                         let val = stack_pop(&mut stack, &pos)?;
                         let addr = stack_pop(&mut stack, &pos)?;
-                        
-                        if addr > crate::MEM_SZ {
-                            lerror!(&op.loc, "Invalid memory address {addr}");
-                            return Ok(1);
-                        }
-
-                        mem[addr] = u64::from(val as u32);
+                        store(&mut mem, addr, 4, val, &pos)?;
                         ip += 1;
                     }
-
-                    #[allow(clippy::cast_possible_truncation)]
                     InstructionType::Write64 => {
                         let val = stack_pop(&mut stack, &pos)?;
                         let addr = stack_pop(&mut stack, &pos)?;
-                        
-                        if addr > crate::MEM_SZ {
-                            lerror!(&op.loc, "Invalid memory address {addr}");
-                            return Ok(1);
-                        }
-
-                        mem[addr] = val as u64;
+                        store(&mut mem, addr, 8, val, &pos)?;
+                        ip += 1;
+                    }
+                    InstructionType::Index => {
+                        let addr = index_address(&mut stack, op)?;
+                        stack.push(addr);
                         ip += 1;
                     }
-        
+                    InstructionType::IndexRead => {
+                        let addr = index_address(&mut stack, op)?;
+                        let (size, signed) = element(&op.text);
+                        stack.push(load(&mem, addr, size, signed, &pos)?);
+                        ip += 1;
+                    }
+                    InstructionType::IndexWrite => {
+                        let val = stack_pop(&mut stack, &pos)?;
+                        let addr = index_address(&mut stack, op)?;
+                        store(&mut mem, addr, element(&op.text).0, val, &pos)?;
+                        ip += 1;
+                    }
+                    InstructionType::Field => {
+                        let a = stack_pop(&mut stack, &pos)?;
+                        stack.push(a + op.value);
+                        ip += 1;
+                    }
+                    InstructionType::FieldRead => {
+                        let addr = stack_pop(&mut stack, &pos)?;
+                        let (size, signed) = element(&op.text);
+                        stack.push(load(&mem, addr, size, signed, &pos)?);
+                        ip += 1;
+                    }
+                    InstructionType::FieldWrite => {
+                        let val = stack_pop(&mut stack, &pos)?;
+                        let addr = stack_pop(&mut stack, &pos)?;
+                        store(&mut mem, addr, element(&op.text).0, val, &pos)?;
+                        ip += 1;
+                    }
+                    //? a function pointer is the ip of its definition
+                    InstructionType::FnAddr => {
+                        stack.push(*functions.get(&op.text).unwrap());
+                        ip += 1;
+                    }
+                    InstructionType::Call => {
+                        let f = stack_pop(&mut stack, &pos)?;
+                        ret_stack.push(ip);
+                        ip = f;
+                    }
+
                     // math
                     InstructionType::Plus => {
                         let a = stack_pop(&mut stack, &pos)?;
                         let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(b + a);
+                        stack.push(b.wrapping_add(a));
                         ip += 1;
                     },
                     InstructionType::Minus => {
                         let a = stack_pop(&mut stack, &pos)?;
                         let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(b - a);
+                        stack.push(b.wrapping_sub(a));
                         ip += 1;
                     },
                     InstructionType::Equals => {
@@ -198,156 +203,192 @@ pub fn run(ops: &[crate::constants::Operator]) -> Result<i32>{
                         stack.push(usize::from(b == a));
                         ip += 1;
                     },
-                    InstructionType::Gt => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(usize::from(b > a));
-                        ip += 1;
-                    },
-                    InstructionType::Lt => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(usize::from(b < a));
-                        ip += 1;
-                    },
                     InstructionType::NotEquals => {
                         let a = stack_pop(&mut stack, &pos)?;
                         let b = stack_pop(&mut stack, &pos)?;
                         stack.push(usize::from(b != a));
                         ip += 1;
                     },
-                    InstructionType::Ge => {
-                        let a = stack_pop(&mut stack, &pos)?;
-                        let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(usize::from(b >= a));
-                        ip += 1;
-                    },
+                    InstructionType::Gt |
+                    InstructionType::Lt |
+                    InstructionType::Ge |
                     InstructionType::Le => {
                         let a = stack_pop(&mut stack, &pos)?;
                         let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(usize::from(b <= a));
+                        let ord = if op.value == 1 { signed(b).cmp(&signed(a)) } else { b.cmp(&a) };
+                        let res = match instruction {
+                            InstructionType::Gt => ord.is_gt(),
+                            InstructionType::Lt => ord.is_lt(),
+                            InstructionType::Ge => ord.is_ge(),
+                            _ => ord.is_le()
+                        };
+                        stack.push(usize::from(res));
                         ip += 1;
                     },
-        
+
                     InstructionType::Band => {
                         let a = stack_pop(&mut stack, &pos)?;
                         let b = stack_pop(&mut stack, &pos)?;
                         stack.push(a & b);
                         ip += 1;
                     }
-        
+
                     InstructionType::Bor => {
                         let a = stack_pop(&mut stack, &pos)?;
                         let b = stack_pop(&mut stack, &pos)?;
                         stack.push(a | b);
                         ip += 1;
                     }
-        
+
+                    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
                     InstructionType::Shr => {
-                        let a = stack_pop(&mut stack, &pos)?;
+                        let a = stack_pop(&mut stack, &pos)? as u32;
                         let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(b >> a);
+                        if op.value == 1 {
+                            stack.push(signed(b).wrapping_shr(a) as usize);
+                        } else {
+                            stack.push(b.wrapping_shr(a));
+                        }
                         ip += 1;
                     }
-        
+
+                    #[allow(clippy::cast_possible_truncation)]
                     InstructionType::Shl => {
-                        let a = stack_pop(&mut stack, &pos)?;
+                        let a = stack_pop(&mut stack, &pos)? as u32;
                         let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(b << a);
+                        stack.push(b.wrapping_shl(a));
                         ip += 1;
                     }
-                    
+
+                    #[allow(clippy::cast_sign_loss)]
                     InstructionType::DivMod => {
                         let a = stack_pop(&mut stack, &pos)?;
                         let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(b / a);
-                        stack.push(b % a);
+                        if a == 0 {
+                            lerror!(&op.loc, "Division by zero");
+                            bail!("");
+                        }
+                        if op.value == 1 {
+                            stack.push(signed(b).wrapping_div(signed(a)) as usize);
+                            stack.push(signed(b).wrapping_rem(signed(a)) as usize);
+                        } else {
+                            stack.push(b / a);
+                            stack.push(b % a);
+                        }
                         ip += 1;
                     }
                     InstructionType::Mul => {
                         let a = stack_pop(&mut stack, &pos)?;
                         let b = stack_pop(&mut stack, &pos)?;
-                        stack.push(b * a);
+                        stack.push(b.wrapping_mul(a));
                         ip += 1;
                     }
-                    InstructionType::Syscall0 => {
-                        todo!();
-                        // ti += 1;
-                    },
-                    InstructionType::Syscall1 => {
-                        todo!();
-                        // ti += 1;
-                    },
-                    InstructionType::Syscall2 => {
-                        todo!();
-                        // ti += 1;
-                    },
-                    InstructionType::Syscall3 => {
-                        let rax = stack_pop(&mut stack, &pos)?;
-                        let rdi = stack_pop(&mut stack, &pos)?;
-                        let rsi = stack_pop(&mut stack, &pos)?;
-                        let rdx = stack_pop(&mut stack, &pos)?;
-                        // println!("yes");
-                        let ret = match rax {
-                            1 => syscalls::sys_write(rax, rdi, rsi, rdx, &mem),
-                            0 => 0, //? temp, so clippy doesnt complain
-                            _ => {
-                                error!("Syscall(3) #{} is not implemented", rax);
-                                return Err(eyre!("Syscall not implemented"));
-                            }
+                    InstructionType::Syscall0 |
+                    InstructionType::Syscall1 |
+                    InstructionType::Syscall2 |
+                    InstructionType::Syscall3 |
+                    InstructionType::Syscall4 |
+                    InstructionType::Syscall5 |
+                    InstructionType::Syscall6 => {
+                        let count = match instruction {
+                            InstructionType::Syscall0 => 0,
+                            InstructionType::Syscall1 => 1,
+                            InstructionType::Syscall2 => 2,
+                            InstructionType::Syscall3 => 3,
+                            InstructionType::Syscall4 => 4,
+                            InstructionType::Syscall5 => 5,
+                            _ => 6
                         };
-                        stack.push(ret);
-                        // println!("{}", stack.len());
+                        let rax = stack_pop(&mut stack, &pos)?;
+                        let mut args = Vec::new();
+                        for _ in 0..count {
+                            args.push(stack_pop(&mut stack, &pos)?);
+                        }
+                        stack.push(syscalls::syscall(rax, &args, &mem, &pos)?);
                         ip += 1;
                     },
-                    InstructionType::Syscall4 => {
-                        todo!();
-                        // ti += 1;
-                    },
-                    InstructionType::Syscall5 => {
-                        todo!();
-                        // ti += 1;
-                    },
-                    InstructionType::Syscall6 => {
-                        todo!();
-                        // ti += 1;
-                    },
                     InstructionType::MemUse => {
-
-                        let m = memories.get(&op.addr.unwrap()).unwrap();
-                        stack.push(m.id);
+                        stack.push(*memories.get(&op.addr.unwrap()).unwrap());
+                        ip += 1;
+                    },
+                    InstructionType::LocalMemUse => {
+                        stack.push(frames.last().unwrap() + op.addr.unwrap());
                         ip += 1;
                     },
                     InstructionType::FnCall => {
                         ret_stack.push(ip);
-                        let f = functions.get(&op.text).unwrap();
-                        ip = f.id;
+                        ip = *functions.get(&op.text).unwrap();
                     }
                     InstructionType::Return => {
+                        frame_top = frames.pop().unwrap();
                         ip = ret_stack.pop().unwrap();
                         ip += 1;
                     }
                     InstructionType::ConstUse => {
-                        let a = constants.get(&op.text).unwrap();
-                        
-                        if let Some(i) = a.value_i {
-                            stack.push(i);
-                        } else if let Some(_s) = a.value_s.clone() {
-                            unimplemented!();
+                        match const_values.get(op.text.as_str()).copied() {
+                            Some(ConstValue::Str(s)) => {
+                                let addr = intern(&mut mem, &mut strings, &mut string_idx, s, &pos)?;
+                                stack.push(s.len());
+                                stack.push(addr);
+                            }
+                            Some(ConstValue::CStr(s)) => {
+                                let addr = intern(&mut mem, &mut strings, &mut string_idx, s, &pos)?;
+                                stack.push(addr);
+                            }
+                            Some(ConstValue::Int(v)) => stack.push(*v),
+                            None => unreachable!()
                         }
                         ip += 1;
                     },
+                    #[allow(clippy::cast_sign_loss)]
+                    InstructionType::CastSized => {
+                        // narrowing casts cut the value down to its size
+                        let a = stack_pop(&mut stack, &pos)?;
+                        let a = match op.text.as_str() {
+                            "cast(u8)" => a & 0xFF,
+                            "cast(u16)" => a & 0xFFFF,
+                            "cast(u32)" => a & 0xFFFF_FFFF,
+                            "cast(i8)" => i64::from(a as u8 as i8) as usize,
+                            "cast(i16)" => i64::from(a as u16 as i16) as usize,
+                            "cast(i32)" => i64::from(a as u32 as i32) as usize,
+                            _ => a
+                        };
+                        stack.push(a);
+                        ip += 1;
+                    },
                     InstructionType::CastBool |
                     InstructionType::CastPtr |
                     InstructionType::CastInt |
                     InstructionType::CastVoid |
+                    InstructionType::CastEnum |
+                    InstructionType::CastStruct |
+                    InstructionType::CastFn |
+                    InstructionType::CastArray |
                     InstructionType::TypeBool |
                     InstructionType::TypePtr |
                     InstructionType::TypeInt |
+                    InstructionType::TypeSized |
                     InstructionType::TypeVoid |
                     InstructionType::TypeAny |
+                    InstructionType::TypeEnum |
+                    InstructionType::TypeStruct |
+                    InstructionType::TypeFn |
+                    InstructionType::TypeArray |
+                    InstructionType::TypeVar |
                     InstructionType::Returns |
                     InstructionType::With => ip += 1,
+                    InstructionType::InlineAsm => {
+                        //? value is 1 if the block has an interpreter fallback
+                        if op.value == 0 {
+                            lerror!(&op.loc, "Inline assembly cannot be interpreted, add an 'else' fallback to the asm block");
+                            bail!("");
+                        }
+                        // skip the signature, the fallback ends with an 'end'
+                        while ops[ip].typ != OpType::Keyword(KeywordType::FunctionThen) {
+                            ip += 1;
+                        }
+                        ip += 1;
+                    },
                     InstructionType::None => unreachable!(),
                 }
 
@@ -355,88 +396,183 @@ pub fn run(ops: &[crate::constants::Operator]) -> Result<i32>{
             OpType::Keyword(k) => {
                 match k {
                     // blocks
-                    KeywordType::If => {
+                    KeywordType::If |
+                    KeywordType::Do => {
                         let a = stack_pop(&mut stack, &pos)?;
                         if a == 0 {
-                            // println!("If({ti}) => t: {:?} j: {}", tokens[token.jmp as usize].typ, token.jmp);
                             ip = op.jmp;
                         } else {
                             ip += 1;
                         }
                     },
-                    KeywordType::Else | KeywordType::End => {
+                    KeywordType::Match => {
+                        let a = stack_pop(&mut stack, &pos)?;
+                        // the arms are chained through their 'end's
+                        let mut i = ip + 1;
                         ip = op.jmp;
-                    }
-                    KeywordType::Do => {
-                        let a = stack.pop().unwrap();
-                        if a == 0 {
-                            ip = op.jmp;
-                        } else {
-                            ip += 1;
+                        loop {
+                            match ops[i].typ {
+                                OpType::Keyword(KeywordType::Case) if ops[i].value == a => {
+                                    ip = i + 1;
+                                    break;
+                                }
+                                OpType::Keyword(KeywordType::Default) => ip = i + 1,
+                                OpType::Keyword(KeywordType::Case) => (),
+                                _ => break
+                            }
+                            i = ops[i].jmp + 1;
                         }
                     }
-                    KeywordType::While | //* exept this one, this one should just skip over
+                    KeywordType::Case | KeywordType::Default => ip += 1,
+                    KeywordType::Else | KeywordType::Elif | KeywordType::End | KeywordType::Break | KeywordType::Continue => {
+                        ip = op.jmp;
+                    }
+                    KeywordType::While |
                     KeywordType::Memory |
+                    KeywordType::ConstantDef => ip += 1,
                     KeywordType::FunctionDef |
-                    KeywordType::FunctionDefExported |
-                    KeywordType::ConstantDef => {
-                        //? Disabled since we now pre run the whole program
-                        // constants.insert(op.text.clone(), Constant { loc: op.loc.clone(), name: op.text.clone(), value_i: Some(op.value), value_s: None, used: false });
+                    KeywordType::FunctionDefExported => {
+                        // value is the size of the functions local memories, the return address is not kept in memory
+                        // but still takes its space like in compiled code
+                        frames.push(frame_top);
+                        frame_top += 8 + op.value;
+                        if frame_top > mem.len() {
+                            lerror!(&op.loc, "Return stack overflow in '{}'", op.text);
+                            bail!("");
+                        }
                         ip += 1;
                     },
                     KeywordType::FunctionDone => {
+                        frame_top = frames.pop().unwrap();
                         if let Some(i) = ret_stack.pop() {
                             ip = i + 1;
                         } else {
                             break;
                         }
                     },
-                    
+
                     KeywordType::FunctionThen  => ip += 1,
                     KeywordType::Constant |
                     KeywordType::Function |
                     KeywordType::Inline |
                     KeywordType::Export |
+                    KeywordType::Struct |
+                    KeywordType::Asm |
+                    KeywordType::Macro |
+                    KeywordType::CondIf |
+                    KeywordType::CondThen |
+                    KeywordType::CondElse |
+                    KeywordType::CondEnd |
+                    KeywordType::Priv |
+                    KeywordType::Enum |
+                    KeywordType::Alloc |
                     KeywordType::Include => unreachable!(),
                 }
             }
-            
+            OpType::Internal(t) => panic!("{t:?}"),
         }
     }
-    
 
     Ok(0)
 }
 
 pub struct Defineds {
-    pub memories: HashMap<usize, Memory>,
-    pub functions: HashMap<String, Function>,
-    pub constants: HashMap<String, Constant>
+    /// memory id => address
+    pub memories: HashMap<usize, usize>,
+    /// function name => ip of its definition
+    pub functions: HashMap<String, usize>,
 }
 
-pub fn pre_run(ops: &[Operator]) -> Defineds {
+/// Finds the functions and lays out the memories, address 0 stays free for NULL
+pub fn pre_run(ops: &[Operator]) -> Result<Defineds> {
     let mut defineds = Defineds{
         memories: HashMap::new(),
         functions: HashMap::new(),
-        constants: HashMap::new(),
     };
+    let mut addr = 8;
     for (ip, op) in ops.iter().enumerate() {
-
         match op.typ {
             OpType::Keyword(KeywordType::Memory) => {
-                defineds.memories.insert(op.addr.unwrap(), Memory { size: op.value, loc: op.loc.clone(), id: op.addr.unwrap() });
-            },
-            OpType::Keyword(KeywordType::FunctionDefExported) => {
-
-            }
-            OpType::Keyword(KeywordType::FunctionDef) => {
-                defineds.functions.insert(op.text.clone(), Function { loc: op.loc.clone(), name: op.text.clone(), id: ip });
+                defineds.memories.insert(op.addr.unwrap(), addr);
+                // struct fields are aligned relative to the start of the memory
+                addr += op.value.div_ceil(8) * 8;
+                if addr > MEM_SZ {
+                    lerror!(&op.loc, "The memories need more than the {} bytes the interpreter has", MEM_SZ);
+                    bail!("");
+                }
             },
-            OpType::Keyword(KeywordType::ConstantDef) => {
-                defineds.constants.insert(op.text.clone(), Constant { loc: op.loc.clone(), name: op.text.clone(), value_i: Some(op.value), value_s: None, used: false });
+            OpType::Keyword(KeywordType::FunctionDef | KeywordType::FunctionDefExported) => {
+                defineds.functions.insert(op.text.clone(), ip);
             },
-            _ => ()   
+            _ => ()
         }
     }
-    defineds
-}
\ No newline at end of file
+    Ok(defineds)
+}
+
+/// The address of the string `s`, writing it to the string memory the first time
+fn intern<'a>(mem: &mut [u8], strings: &mut HashMap<&'a str, usize>, string_idx: &mut usize, s: &'a str, pos: &Loc) -> Result<usize> {
+    if let Some(addr) = strings.get(s) {
+        return Ok(*addr);
+    }
+    if *string_idx + s.len() > STRING_SZ {
+        lerror!(pos, "The strings need more than the {} bytes the interpreter has", STRING_SZ);
+        bail!("");
+    }
+    let addr = MEM_SZ + *string_idx;
+    mem[addr..addr + s.len()].copy_from_slice(s.as_bytes());
+    *string_idx += s.len();
+    strings.insert(s, addr);
+    Ok(addr)
+}
+
+/// Reads `size` bytes at `addr`, sign extending them if `signed`
+#[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+fn load(mem: &[u8], addr: usize, size: usize, signed: bool, pos: &Loc) -> Result<usize> {
+    let Some(bytes) = mem.get(addr..addr.saturating_add(size)) else {
+        lerror!(pos, "Invalid memory address {addr}");
+        bail!("");
+    };
+    let mut buf = [0; 8];
+    buf[..size].copy_from_slice(bytes);
+    let val = u64::from_le_bytes(buf);
+    if signed && size < 8 {
+        let shift = 64 - size * 8;
+        return Ok((((val << shift) as i64) >> shift) as usize);
+    }
+    Ok(val as usize)
+}
+
+/// Writes the lowest `size` bytes of `val` to `addr`
+fn store(mem: &mut [u8], addr: usize, size: usize, val: usize, pos: &Loc) -> Result<()> {
+    let Some(bytes) = mem.get_mut(addr..addr.saturating_add(size)) else {
+        lerror!(pos, "Invalid memory address {addr}");
+        bail!("");
+    };
+    bytes.copy_from_slice(&(val as u64).to_le_bytes()[..size]);
+    Ok(())
+}
+
+/// Pops an index and the array under it and gives the address of the element, checking the index like debug builds do
+fn index_address(stack: &mut Vec<usize>, op: &Operator) -> Result<usize> {
+    let idx = stack_pop(stack, &op.loc)?;
+    let arr = stack_pop(stack, &op.loc)?;
+    // the length of arrays passed to functions is not known
+    if op.value != 0 && idx >= op.value {
+        lerror!(&op.loc, "Index out of bounds");
+        bail!("");
+    }
+    Ok(arr + idx * element(&op.text).0)
+}
+
+/// The size and signedness of the element type `elem`
+#[allow(clippy::cast_possible_truncation)]
+fn element(elem: &str) -> (usize, bool) {
+    let t = Types::from_element(elem);
+    (t.get_size() as usize, t.is_signed())
+}
+
+#[allow(clippy::cast_possible_wrap)]
+fn signed(a: usize) -> i64 {
+    a as i64
+}
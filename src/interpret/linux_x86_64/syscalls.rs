@@ -1,22 +1,46 @@
+use std::io::Write;
+
+use anyhow::{Result, bail};
+
+use crate::{definitions::Loc, lerror};
+
+/// Runs syscall `n`, `args` are rdi, rsi, rdx and so on
 #[allow(clippy::cast_possible_truncation)]
-pub fn sys_write(sys_n: usize, fd: usize, buff: usize, count: usize, mem: &Vec<u64> ) -> usize {
-    let mem = (*mem).clone();
-    // println!("{:?}", &mem[buff..(buff + count)]);
-    // return 0 ;
-    let s = &mem[buff..(buff + count)].iter().map(|i| {
-        char::from_u32(u32::from(*i as u8)).unwrap_or('_').to_string()
-    }).collect::<String>();
-    
+pub fn syscall(n: usize, args: &[usize], mem: &[u8], pos: &Loc) -> Result<usize> {
+    match (n, args) {
+        (1, &[fd, buff, count, ..]) => sys_write(fd, buff, count, mem, pos),
+        (60, &[code, ..]) => {
+            let _ = std::io::stdout().flush();
+            std::process::exit(code as i32);
+        }
+        _ => {
+            lerror!(pos, "Syscall #{} with {} arguments cannot be interpreted", n, args.len());
+            bail!("");
+        }
+    }
+}
+
+fn sys_write(fd: usize, buff: usize, count: usize, mem: &[u8], pos: &Loc) -> Result<usize> {
+    let Some(s) = mem.get(buff..buff.saturating_add(count)) else {
+        lerror!(pos, "Invalid memory address {buff}");
+        bail!("");
+    };
+
     match fd {
         1 => {
-            print!("{s}");
+            let mut out = std::io::stdout();
+            out.write_all(s)?;
+            out.flush()?;
         },
         2 => {
-            eprint!("{s}");
+            let mut out = std::io::stderr();
+            out.write_all(s)?;
+            out.flush()?;
         },
-        _ => panic!("Unknown file {fd}")
+        _ => {
+            lerror!(pos, "Writing to file descriptor {} cannot be interpreted", fd);
+            bail!("");
+        }
     };
-    let _ = std::io::Write::flush(&mut std::io::stdout());
-    let _ = std::io::Write::flush(&mut std::io::stderr());
-    sys_n
-}
\ No newline at end of file
+    Ok(count)
+}
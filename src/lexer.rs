@@ -1,9 +1,11 @@
 
-use crate::{definitions::{Token, TokenType}, Args};
+use anyhow::{Result, bail};
+
+use crate::{definitions::{self, Token, TokenType}, lerror, types::common::Loc, Args};
 
 fn lex_word(s: String, tok_type: TokenType) -> (TokenType, String) {
     match s {
-        s if s.parse::<u64>().is_ok() && tok_type == TokenType::Word => { // negative numbers not yet implemented
+        s if is_int_literal(&s) && tok_type == TokenType::Word => {
             (TokenType::Int, s)
         },
         s if tok_type == TokenType::Word => {
@@ -18,130 +20,397 @@ fn lex_word(s: String, tok_type: TokenType) -> (TokenType, String) {
         s if tok_type == TokenType::Char => {
             (TokenType::Char, s)
         }
+        s if tok_type == TokenType::DocComment => {
+            (TokenType::DocComment, s)
+        }
+        s if tok_type == TokenType::Asm => {
+            (TokenType::Asm, s)
+        }
         _ => unreachable!()
     }
 }
 
-pub fn find_col<F>(text: &str, mut col: usize, predicate: F) -> usize where F: Fn(char, char) -> bool {
-    let mut last = '\0';
-    while col < text.len() && !predicate(text.chars().nth(col).unwrap(), last) {
-        last = text.chars().nth(col).unwrap();
-        col += 1;
+/// Words starting with a digit, or a '-' followed by a digit, are integer literals
+pub fn is_int_literal(s: &str) -> bool {
+    let s = s.strip_prefix('-').unwrap_or(s);
+    s.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Parses `42`, `-42`, `0x1F`, `0b1010`, `0o755` and `1_000_000` style integer literals,
+/// negative numbers are stored as two's complement
+pub fn parse_int(s: &str) -> Result<usize, String> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(d) => (true, d),
+        None => (false, s)
+    };
+    let (radix, digits) = match digits.get(..2) {
+        Some("0x" | "0X") => (16, &digits[2..]),
+        Some("0b" | "0B") => (2, &digits[2..]),
+        Some("0o" | "0O") => (8, &digits[2..]),
+        _ => (10, digits)
+    };
+
+    if digits.is_empty() || digits.starts_with('_') || digits.ends_with('_') {
+        return Err(format!("Invalid integer literal '{s}'"));
     }
 
-    col
+    let mut n: u64 = 0;
+    for c in digits.chars().filter(|c| *c != '_') {
+        let Some(d) = c.to_digit(radix) else {
+            return Err(format!("Invalid digit '{c}' in base {radix} integer literal '{s}'"));
+        };
+        n = n.checked_mul(u64::from(radix))
+            .and_then(|n| n.checked_add(u64::from(d)))
+            .ok_or_else(|| format!("Integer literal '{s}' does not fit in 64 bits"))?;
+    }
+
+    if negative {
+        if n > 1 << 63 {
+            return Err(format!("Integer literal '{s}' does not fit in a signed 64 bit integer"));
+        }
+        #[allow(clippy::cast_possible_wrap)]
+        return Ok((n as i64).wrapping_neg() as usize);
+    }
+    Ok(n as usize)
 }
 
+/// Decodes the escape sequences of a string or char literal, on failure returns the byte offset
+/// of every bad escape and what is wrong with it
+pub fn unescape(s: &str) -> Result<String, Vec<(usize, String)>> {
+    let mut out = String::with_capacity(s.len());
+    let mut errors = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match escape(&mut chars) {
+            Ok(c) => out.push(c),
+            Err(msg) => errors.push((i, msg))
+        }
+    }
+    if errors.is_empty() {
+        Ok(out)
+    } else {
+        Err(errors)
+    }
+}
 
-// TODO: Implement multiline strings
-fn lex_line(text: &str) -> Vec<(usize, String, TokenType)> {
-    let mut tokens: Vec<(usize, String, TokenType)> = Vec::new();
-
-    let mut col = find_col(text, 0, |x, _| !x.is_whitespace());
-    let mut col_end: usize = 0;
-    while col_end < text.to_string().len() {
-        if (text.len() - col) < 1 {
-            return tokens;
-        }
-        if &text[col..=col] == "\"" {
-            col_end = find_col(text, col + 1, |x, x2| x == '"' && x2 != '\\');
-            let t = &text[(col + 1)..col_end];
-            let t = t.replace("\\n", "\n")
-                                .replace("\\t", "\t")
-                                .replace("\\r", "\r")
-                                .replace("\\\'", "\'")
-                                .replace("\\\"", "\"")
-                                .replace("\\0", "\0");
-            if !t.is_empty() {
-                tokens.push((col, t.to_string(), TokenType::String));
+/// Decodes one escape sequence, the '\\' is already taken
+fn escape(chars: &mut std::iter::Peekable<std::str::CharIndices>) -> Result<char, String> {
+    let Some((_, e)) = chars.next() else {
+        return Err("Unfinished escape sequence".to_string());
+    };
+    match e {
+        'n' => Ok('\n'),
+        't' => Ok('\t'),
+        'r' => Ok('\r'),
+        '0' => Ok('\0'),
+        '\\' => Ok('\\'),
+        '\'' => Ok('\''),
+        '"' => Ok('"'),
+        'x' => {
+            let hex: String = (0..2).filter_map(|_| chars.next_if(|(_, c)| c.is_ascii_hexdigit())).map(|(_, c)| c).collect();
+            match u8::from_str_radix(&hex, 16) {
+                Ok(b) if hex.len() == 2 && b < 0x80 => Ok(char::from(b)),
+                _ => Err("Expected '\\xNN' with NN being 2 hex digits of at most 7F".to_string())
             }
-            col = find_col(text, col_end + 1, |x, _| !x.is_whitespace());
-
-        } else if &text[col..=col] == "'"{
-            col_end = find_col(text, col + 1, |x, x2| x == '\'' && x2 != '\\');
-            let t = &text[(col + 1)..col_end];
-            let t = t.replace("\\n", "\n")
-                                .replace("\\t", "\t")
-                                .replace("\\r", "\r")
-                                .replace("\\\'", "\'")
-                                .replace("\\\"", "\"")
-                                .replace("\\0", "\0");
-
-            
-            if !t.is_empty() {
-                tokens.push((col, t.to_string(), TokenType::Char));
+        }
+        'u' => {
+            if chars.next_if(|(_, c)| *c == '{').is_none() {
+                return Err("Expected '{' after '\\u'".to_string());
             }
-            col = find_col(text, col_end + 1, |x, _| !x.is_whitespace());
-
-        } else {
-
-            if &text[col..=col] == "c" && text.len() - 1 + col > 0 && &text[col+1..=col+1] == "\"" {
-                col_end = find_col(text, col + 2, |x, x2| x == '"' && x2 != '\\');
-                let t = &text[(col + 2)..col_end];
-                let mut t = t.replace("\\n", "\n")
-                                    .replace("\\t", "\t")
-                                    .replace("\\r", "\r")
-                                    .replace("\\\'", "\'")
-                                    .replace("\\\"", "\"")
-                                    .replace("\\0", "\0");
-                
-                if !t.is_empty() {
-                    t.push('\0');
-                    tokens.push((col, t.to_string(), TokenType::CString));
-                }
-                col = find_col(text, col_end + 1, |x, _| !x.is_whitespace());
-                
+            let mut hex = String::new();
+            while let Some((_, c)) = chars.next_if(|(_, c)| *c != '}') {
+                hex.push(c);
+            }
+            if chars.next().is_none() {
+                return Err("Unclosed '\\u{'".to_string());
+            }
+            u32::from_str_radix(&hex, 16).ok()
+                .filter(|_| (1..=6).contains(&hex.len()))
+                .and_then(char::from_u32)
+                .ok_or_else(|| format!("'\\u{{{hex}}}' is not a valid unicode escape"))
+        }
+        e => Err(format!("Unknown escape sequence '\\{e}'")),
+    }
+}
+
+struct Lexer<'a> {
+    src: &'a str,
+    file: &'a str,
+    pos: usize,
+    ln: usize,
+    col: usize,
+    tokens: Vec<Token>,
+    // index of the first token on the current line
+    line_start: usize,
+    errors: Vec<(definitions::Loc, String)>,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(src: &'a str, file: &'a str) -> Self {
+        Self { src, file, pos: 0, ln: 1, col: 0, tokens: Vec::new(), line_start: 0, errors: Vec::new() }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.src.as_bytes().get(self.pos).copied()
+    }
+
+    fn at(&self, s: &str) -> bool {
+        self.src.as_bytes()[self.pos..].starts_with(s.as_bytes())
+    }
+
+    /// Moves one byte forward, columns are counted in chars so utf-8 continuation bytes dont count
+    fn bump(&mut self) {
+        let b = self.src.as_bytes()[self.pos];
+        self.pos += 1;
+        if b == b'\n' {
+            self.ln += 1;
+            self.col = 0;
+        } else if b & 0xC0 != 0x80 {
+            self.col += 1;
+        }
+    }
+
+    fn bump_while(&mut self, f: impl Fn(&Self, u8) -> bool) {
+        while let Some(b) = self.peek() {
+            if !f(self, b) {
+                break;
+            }
+            self.bump();
+        }
+    }
+
+    /// `'a` without a closing quote is a type variable, not a char
+    fn at_type_var(&self) -> bool {
+        let rest = &self.src.as_bytes()[self.pos + 1..];
+        let len = rest.iter().take_while(|b| b.is_ascii_alphanumeric() || **b == b'_').count();
+        len > 0 && rest.get(len).is_none_or(|b| b.is_ascii_whitespace() || *b == b')')
+    }
+
+    fn here(&self) -> Loc {
+        Loc::new(self.file, self.pos, self.ln, self.col)
+    }
+
+    /// Location of byte `off` inside the span that starts at `from`
+    fn loc_at(&self, from: &Loc, off: usize) -> definitions::Loc {
+        let before = &self.src[from.start..off];
+        match before.rfind('\n') {
+            Some(nl) => (self.file.to_string(), from.ln + before.matches('\n').count(), before[nl + 1..].chars().count()),
+            None => (self.file.to_string(), from.ln, from.col + before.chars().count())
+        }
+    }
+
+    fn push(&mut self, mut span: Loc, text: String, typ: TokenType) {
+        span.end = self.pos;
+        let (typ, text) = lex_word(text, typ);
+        self.tokens.push(Token {
+            span,
+            text,
+            typ,
+            value: None,
+            addr: None,
+            op_typ: crate::definitions::OpType::Instruction(crate::definitions::InstructionType::None)
+        });
+    }
+
+    fn lex(&mut self) {
+        while let Some(b) = self.peek() {
+            if b == b'\n' {
+                self.bump();
+                self.end_line();
+            } else if b.is_ascii_whitespace() {
+                self.bump();
+            } else if self.at("///") {
+                self.doc_comment();
+            } else if self.at("//") {
+                self.bump_while(|_, b| b != b'\n');
+            } else if self.at("/*") {
+                self.block_comment();
+            } else if let Some((prefix, typ, raw)) = [("\"", TokenType::String, false), ("c\"", TokenType::CString, false),
+                                                      ("r\"", TokenType::String, true), ("cr\"", TokenType::CString, true)]
+                .into_iter()
+                .find(|(p, _, _)| self.at(p)) {
+                self.string(prefix.len(), typ, raw);
+            } else if b == b'\'' && !self.at_type_var() {
+                self.char();
             } else {
-                col_end = find_col(text, col, |x, _| x.is_whitespace());
-                let t = &text[col..col_end];
-                
-                if t == "//" {
-                    return tokens;
-                }
-                
-                if !t.is_empty() {
-                    tokens.push((col, t.to_string(), TokenType::Word));
+                self.word();
+            }
+        }
+    }
+
+    /// Called after every newline, starts capturing the raw body if the line was `... asm ... then`
+    fn end_line(&mut self) {
+        let line = &self.tokens[self.line_start..];
+        let is_word = |t: &Token, w: &str| t.typ == TokenType::Word && t.text == w;
+        if line.iter().any(|t| is_word(t, "asm")) && line.last().is_some_and(|t| is_word(t, "then")) {
+            self.asm_body();
+        }
+        self.line_start = self.tokens.len();
+    }
+
+    /// The body of an asm block is copied as is until a line that is only 'else' or 'done'
+    fn asm_body(&mut self) {
+        let span = self.here();
+        let mut body = String::new();
+        while self.pos < self.src.len() {
+            let rest = &self.src[self.pos..];
+            let line = &rest[..rest.find('\n').unwrap_or(rest.len())];
+            if matches!(line.trim(), "else" | "done") {
+                self.push(span, body, TokenType::Asm);
+                return;
+            }
+            body.push_str(line.trim_end_matches('\r'));
+            body.push('\n');
+            self.bump_while(|_, b| b != b'\n');
+            if self.peek().is_some() {
+                self.bump();
+            }
+        }
+    }
+
+    /// Doc comments are kept so they can be attached to the next definition
+    fn doc_comment(&mut self) {
+        let span = self.here();
+        let start = self.pos + 3;
+        self.bump_while(|_, b| b != b'\n');
+        let doc = &self.src[start..self.pos];
+        let doc = doc.strip_prefix(' ').unwrap_or(doc).trim_end().to_string();
+        self.push(span, doc, TokenType::DocComment);
+    }
+
+    /// Skips a `/* */` comment, which can be nested
+    fn block_comment(&mut self) {
+        let span = self.here();
+        let mut depth = 0;
+        while self.pos < self.src.len() {
+            if self.at("/*") {
+                depth += 1;
+                self.bump();
+            } else if self.at("*/") {
+                depth -= 1;
+                self.bump();
+            }
+            self.bump();
+            if depth == 0 {
+                return;
+            }
+        }
+        self.errors.push((span.as_tuple(), "Unterminated block comment".to_string()));
+    }
+
+    /// Reads until the closing `quote`, skipping over escaped characters unless `raw`,
+    /// returns false if the literal was not closed before the end of the line or file
+    fn until_quote(&mut self, quote: u8, raw: bool, multiline: bool) -> bool {
+        while let Some(b) = self.peek() {
+            if b == quote {
+                return true;
+            }
+            if b == b'\n' && !multiline {
+                return false;
+            }
+            self.bump();
+            if b == b'\\' && !raw && self.peek().is_some_and(|b| b != b'\n' || multiline) {
+                self.bump();
+            }
+        }
+        false
+    }
+
+    /// Decodes the escapes in a literal that starts at `span`, the body starts at byte `start`
+    fn unescape(&mut self, span: &Loc, start: usize, body: &str) -> Option<String> {
+        match unescape(body) {
+            Ok(t) => Some(t),
+            Err(errors) => {
+                for (i, msg) in errors {
+                    let loc = self.loc_at(span, start + i);
+                    self.errors.push((loc, msg));
                 }
-                col = find_col(text, col_end, |x, _| !x.is_whitespace());
+                None
+            }
+        }
+    }
+
+    /// Strings can go on over multiple lines
+    fn string(&mut self, prefix: usize, typ: TokenType, raw: bool) {
+        let span = self.here();
+        for _ in 0..prefix {
+            self.bump();
+        }
+        let start = self.pos;
+        if !self.until_quote(b'"', raw, true) {
+            let what = if typ == TokenType::CString { "cstring" } else { "string" };
+            self.errors.push((span.as_tuple(), format!("Unterminated {what} literal")));
+            // go on after the line it started on so the errors after it are still found
+            self.pos = span.start;
+            self.ln = span.ln;
+            self.col = span.col;
+            self.bump_while(|_, b| b != b'\n');
+            return;
+        }
+        let body = self.src[start..self.pos].replace("\r\n", "\n");
+        self.bump();
+
+        let text = if raw { Some(body) } else { self.unescape(&span, start, &body) };
+        if let Some(mut text) = text {
+            if typ == TokenType::CString {
+                text.push('\0');
             }
+            self.push(span, text, typ);
+        }
+    }
+
+    fn char(&mut self) {
+        let span = self.here();
+        self.bump();
+        let start = self.pos;
+        if !self.until_quote(b'\'', false, false) {
+            self.errors.push((span.as_tuple(), "Unterminated char literal".to_string()));
+            return;
+        }
+        let body = self.src[start..self.pos].to_string();
+        self.bump();
+        if body.is_empty() {
+            self.errors.push((span.as_tuple(), "Empty char literal".to_string()));
+            return;
+        }
+        if let Some(t) = self.unescape(&span, start, &body) {
+            self.push(span, t, TokenType::Char);
+        }
+    }
+
+    /// Words go until whitespace, comments can start in the middle of a word
+    fn word(&mut self) {
+        let span = self.here();
+        self.bump_while(|l, b| !b.is_ascii_whitespace() && !l.at("//") && !l.at("/*"));
+        let word = &self.src[span.start..self.pos];
+        if let Some(i) = word.find(|c: char| c.is_control()) {
+            let c = word[i..].chars().next().unwrap();
+            let loc = self.loc_at(&span, span.start + i);
+            self.errors.push((loc, format!("Invalid character {c:?}")));
+            return;
+        }
+        let text = self.src[span.start..self.pos].to_string();
+        self.push(span, text, TokenType::Word);
+        if self.at("//") {
+            self.bump_while(|_, b| b != b'\n');
         }
     }
-    tokens
 }
 
-pub fn lex(code: &str, file: &str, _args: &Args) -> Vec<Token> {
-    let lines: Vec<(usize, &str)> = code
-        .split(['\n', '\r'])
-        .enumerate()
-        .collect();
-    
-    let lines: Vec<(usize, String)> = lines.iter().map(|i| (i.0, i.1.to_string())).collect();
-
-    let mut tokens: Vec<Token> = Vec::new();
-
-    for (row, line) in lines {
-        let lt = lex_line(&line);
-        for (col, tok, tok_type) in lt {
-            let (tok_type, tok) = lex_word(tok, tok_type);
-            let t = Token{
-                file: file.to_string(),
-                line: row + 1,
-                col,
-                text: tok,
-                typ: tok_type,
-                value: None,
-                addr: None,
-                op_typ: crate::definitions::OpType::Instruction(crate::definitions::InstructionType::None)
-            };
-            tokens.push(t);
-        }
-    }
-    // println!("{}", tokens.len());
-
-    // for token in tokens.clone() {
-    //     println!("tok: {:?}", token.text);
-    // }
-    
-
-    tokens
-}
\ No newline at end of file
+pub fn lex(code: &str, file: &str, _args: &Args) -> Result<Vec<Token>> {
+    let mut lexer = Lexer::new(code, file);
+    lexer.lex();
+
+    if !lexer.errors.is_empty() {
+        for (loc, msg) in &lexer.errors {
+            lerror!(loc, "{msg}");
+        }
+        bail!("");
+    }
+
+    Ok(lexer.tokens)
+}
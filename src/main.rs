@@ -3,6 +3,7 @@
 mod definitions;
 mod util;
 mod compile;
+mod interpret;
 mod parser;
 mod lexer;
 mod preprocessor;
@@ -10,8 +11,11 @@ mod typechecker;
 mod precompiler;
 mod config;
 mod errors;
-pub mod test;
+mod coverage;
+mod emit;
+mod types;
 use config::*;
+use emit::Emit;
 use std::{fs, collections::HashMap};
 
 use clap::Parser;
@@ -53,7 +57,24 @@ pub struct Args {
 
     // disables the main function
     #[arg(long="lib")]
-    lib_mode: bool
+    lib_mode: bool,
+
+    /// Count how often every block runs, the counts get appended to '<out_file>.cov' when the program exits,
+    /// use 'mclangc cov <out_file>' to turn them into lcov.info
+    #[arg(long)]
+    coverage: bool,
+
+    /// Define NAME, or NAME=VALUE, for '#if' conditions, NAME alone is 1
+    #[arg(long="define", short='D')]
+    defines: Vec<String>,
+
+    /// How many levels deep macros can expand into other macros
+    #[arg(long, default_value_t=64)]
+    macro_depth: usize,
+
+    /// Stop after these stages and write them next to the output file, comma separated
+    #[arg(long, value_delimiter=',')]
+    emit: Vec<Emit>,
     //#[arg(long, short='F')]
     //features: Vec<String>,
 
@@ -76,10 +97,27 @@ impl Args {
             }
         }
     }
+
+    /// Checks if the output of a stage was requested, without '--emit' only the executable is
+    pub fn emits(&self, stage: Emit) -> bool {
+        self.emit.contains(&stage) || (self.emit.is_empty() && stage == Emit::Exe)
+    }
+
+    /// The last stage of the pipeline that needs to run
+    pub fn last_stage(&self) -> Emit {
+        self.emit.iter().max().copied().unwrap_or(Emit::Exe)
+    }
 }
 
 fn main() -> Result<()>{
 
+    if std::env::args().nth(1).as_deref() == Some("cov") {
+        let args = coverage::CovArgs::parse_from(std::env::args().skip(1));
+        if coverage::cov(&args).is_err() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
     let args = Args::parse();
 
@@ -88,11 +126,44 @@ fn main() -> Result<()>{
         return Ok(());
     };
     
-    let tokens = lexer::lex(&code, args.in_file.as_str(), &args);
+    let tokens = match lexer::lex(&code, args.in_file.as_str(), &args) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Lexing failed, exiting!");
+            if crate::DEV_MODE {
+                return Err(e)
+            }
+            return Ok(());
+        }
+    };
 
+    if args.emits(Emit::Tokens) {
+        emit::tokens(&Emit::Tokens.path(&args), &tokens, args.quiet)?;
+    }
+    if args.last_stage() == Emit::Tokens {
+        return Ok(());
+    }
     
     let mut parser = parser::Parser::new(tokens, &args, None);
-    let program = match parser.parse(){
+
+    if args.emits(Emit::Ops) {
+        let ops = match parser.ops() {
+            Ok(t) => t,
+            Err(e) => {
+                error!("Parsing failed, exiting!");
+                if crate::DEV_MODE {
+                    return Err(e)
+                }
+                return Ok(());
+            }
+        };
+        emit::ops(&Emit::Ops.path(&args), &ops, args.quiet)?;
+    }
+    if args.last_stage() == Emit::Ops {
+        return Ok(());
+    }
+
+    let mut program = match parser.parse(){
         Ok(t) => t,
         Err(e) => {
             error!("Parsing failed, exiting!");
@@ -103,7 +174,22 @@ fn main() -> Result<()>{
         }
     };
 
-    match typechecker::typecheck(program.ops.clone(), &args, None, HashMap::new(), HashMap::new()) {
+    if args.emits(Emit::Expanded) {
+        emit::ops(&Emit::Expanded.path(&args), &program.ops, args.quiet)?;
+    }
+    if args.last_stage() == Emit::Expanded {
+        return Ok(());
+    }
+
+    if args.emits(Emit::Docs) {
+        emit::docs(&Emit::Docs.path(&args), &program, args.quiet)?;
+    }
+    if args.last_stage() == Emit::Docs {
+        return Ok(());
+    }
+
+    let mut lowering = typechecker::Lowering::default();
+    match typechecker::typecheck(program.ops.clone(), &args, None, HashMap::new(), HashMap::new(), 0, None, &mut lowering) {
         Ok(_) => (),
         Err(e) => {
             error!("Typechecking failed, exiting!");
@@ -113,6 +199,24 @@ fn main() -> Result<()>{
             return Ok(());
         }
     };
+    for ip in lowering.signed {
+        program.ops[ip].value = 1;
+    }
+    for (ip, elem, count) in lowering.elements {
+        program.ops[ip].text = elem;
+        program.ops[ip].value = count;
+    }
+
+    if args.interpret {
+        let c = match interpret::linux_x86_64::run(&program) {
+            Ok(c) => c,
+            Err(_) => {
+                error!("Interpretation failed, exiting!");
+                1
+            }
+        };
+        std::process::exit(c);
+    }
 
     let c =match compile::linux_x86_64::compile(&program, &args) {
         Ok(c) => c,
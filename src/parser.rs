@@ -1,35 +1,99 @@
+use std::collections::HashMap;
 use std::ops::Deref;
 
-use crate::{definitions::{Operator, OpType, Token, TokenType, Loc, KeywordType, InstructionType, InternalType, Program}, lerror, preprocessor::Preprocessor, Args};
+use crate::{definitions::{Operator, OpType, Token, TokenType, Loc, KeywordType, InstructionType, InternalType, Program}, lerror, linfo, lexer, preprocessor::Preprocessor, Args};
 use anyhow::{Result, bail};
 
 pub fn cross_ref(mut program: Vec<Operator>) -> Result<Vec<Operator>> {
     let mut stack: Vec<usize> = Vec::new();
+    // 'break's waiting for the 'end' of their loop, as (ip of the loops 'do', ip of the 'break')
+    let mut breaks: Vec<(usize, usize)> = Vec::new();
+    // the 'elif's of each 'if' chain, by the ip of the chains branch that is open right now
+    let mut elifs: HashMap<usize, Vec<usize>> = HashMap::new();
+    // the arms of each 'match', by the ip of the 'match'
+    let mut arms: HashMap<usize, Vec<usize>> = HashMap::new();
 
     for ip in 0..program.len() {
         let op = &program.clone()[ip];
         // println!("{op:?}");
+        if stack.last().is_some_and(|&i| program[i].typ == OpType::Keyword(KeywordType::Match)) &&
+            !matches!(op.typ, OpType::Keyword(KeywordType::Case | KeywordType::Default | KeywordType::End)) {
+            lerror!(&op.loc, "Only 'case' and 'default' can be in a 'match', found '{}'", op.typ.human());
+            op.note_expansion();
+            bail!("Bad block")
+        }
         match op.typ {
             // OpType::Keyword(KeywordType::FunctionDef) |
-            OpType::Keyword(KeywordType::If | KeywordType::While) => {
+            OpType::Keyword(KeywordType::If | KeywordType::While | KeywordType::Match) |
+            OpType::Instruction(InstructionType::InlineAsm) => {
                 stack.push(ip);
             }
             OpType::Keyword(KeywordType::Else) => {
                 let Some(if_ip) = stack.pop() else {
                     lerror!(&op.loc, "Unclosed-if else block");
+                    op.note_expansion();
                     bail!("Cross referencing")
                 };
-                if program[if_ip].typ != OpType::Keyword(KeywordType::If) {
-                    lerror!(&op.clone().loc,"'else' can only close 'if' blocks");
+                if program[if_ip].typ != OpType::Keyword(KeywordType::If) && !elifs.contains_key(&if_ip) {
+                    lerror!(&op.clone().loc,"'else' can only close 'if' or 'elif' blocks");
+                    op.note_expansion();
                     bail!("Bad block")
                 }
                 
                 program[if_ip].jmp = ip + 1;
+                if let Some(chain) = elifs.remove(&if_ip) {
+                    elifs.insert(ip, chain);
+                }
+                stack.push(ip);
+            },
+            OpType::Keyword(KeywordType::Case | KeywordType::Default) => {
+                let Some(&match_ip) = stack.last().filter(|&&i| program[i].typ == OpType::Keyword(KeywordType::Match)) else {
+                    lerror!(&op.loc, "'{}' can only be used in a 'match'", op.typ.human());
+                    op.note_expansion();
+                    bail!("Bad block")
+                };
+
+                let match_arms = arms.entry(match_ip).or_default();
+                for &arm in match_arms.iter() {
+                    if program[arm].typ == OpType::Keyword(KeywordType::Default) {
+                        lerror!(&op.loc, "'default' has to be the last arm of a 'match'");
+                        linfo!(&program[arm].loc, "'default' is here");
+                        op.note_expansion();
+                        bail!("Bad block")
+                    }
+                    if op.typ == OpType::Keyword(KeywordType::Case) && program[arm].value == op.value {
+                        lerror!(&op.loc, "Case {} is already handled in this 'match'", op.value);
+                        linfo!(&program[arm].loc, "first case here");
+                        op.note_expansion();
+                        bail!("Bad block")
+                    }
+                }
+                match_arms.push(ip);
+                stack.push(ip);
+            }
+            OpType::Keyword(KeywordType::Elif) => {
+                let Some(block_ip) = stack.pop() else {
+                    lerror!(&op.loc, "'elif' without an 'if'");
+                    op.note_expansion();
+                    bail!("Cross referencing")
+                };
+                if program[block_ip].typ != OpType::Keyword(KeywordType::If) && !elifs.contains_key(&block_ip) {
+                    lerror!(&op.loc, "'elif' can only close 'if' or 'elif' blocks");
+                    op.note_expansion();
+                    bail!("Bad block")
+                }
+
+                // a false condition tries the next branch, the end of a branch jumps past the whole chain
+                program[block_ip].jmp = ip + 1;
+                let mut chain = elifs.remove(&block_ip).unwrap_or_default();
+                chain.push(ip);
+                elifs.insert(ip, chain);
                 stack.push(ip);
             },
             OpType::Keyword(KeywordType::End) => {
                 let Some(block_ip) = stack.pop() else {
                     lerror!(&op.loc, "Unclosed if, if-else, while-do, function, memory, or constant");
+                    op.note_expansion();
                     bail!("Cross referencing")
                 };
 
@@ -37,29 +101,93 @@ pub fn cross_ref(mut program: Vec<Operator>) -> Result<Vec<Operator>> {
                     OpType::Keyword(KeywordType::If | KeywordType::Else) => {
                         program[block_ip].jmp = ip;
                         program[ip].jmp = ip + 1;
+                        for elif_ip in elifs.remove(&block_ip).unwrap_or_default() {
+                            program[elif_ip].jmp = ip;
+                        }
+                    }
+
+                    // the last branch of an 'if' chain
+                    OpType::Keyword(KeywordType::Do) if elifs.contains_key(&block_ip) => {
+                        program[block_ip].jmp = ip;
+                        program[ip].jmp = ip + 1;
+                        for elif_ip in elifs.remove(&block_ip).unwrap_or_default() {
+                            program[elif_ip].jmp = ip;
+                        }
                     }
 
                     OpType::Keyword(KeywordType::Do) => {
                         program[ip].jmp = program[block_ip].jmp;
                         program[block_ip].jmp = ip + 1;
+                        for &(_, break_ip) in breaks.iter().filter(|(do_ip, _)| *do_ip == block_ip) {
+                            program[break_ip].jmp = ip + 1;
+                        }
+                        breaks.retain(|(do_ip, _)| *do_ip != block_ip);
                     }
                     
+                    // skips over the interpreter fallback
+                    OpType::Instruction(InstructionType::InlineAsm) => {
+                        program[block_ip].jmp = ip + 1;
+                        program[ip].jmp = ip + 1;
+                    }
+
+                    // the 'end' of an arm jumps past the 'match', which is not known yet
+                    OpType::Keyword(KeywordType::Case | KeywordType::Default) => {
+                        program[block_ip].jmp = ip;
+                    }
+
+                    OpType::Keyword(KeywordType::Match) => {
+                        program[block_ip].jmp = ip;
+                        program[ip].jmp = ip + 1;
+                        for arm in arms.remove(&block_ip).unwrap_or_default() {
+                            let arm_end = program[arm].jmp;
+                            program[arm_end].jmp = ip;
+                        }
+                    }
+
                     OpType::Keyword(KeywordType::Memory | KeywordType::Constant) => (),
 
+                    OpType::Keyword(KeywordType::Elif) => {
+                        lerror!(&program[block_ip].loc, "Expected 'do' after the 'elif' condition");
+                        program[block_ip].note_expansion();
+                        bail!("Bad block")
+                    }
+
                     a => {
                         println!("{a:?}");
-                        lerror!(&op.clone().loc,"'end' can only close if, if-else, while-do, function, memory, or constant blocks");
+                        lerror!(&op.clone().loc,"'end' can only close if, if-else, while-do, match, case, function, memory, or constant blocks");
+                        op.note_expansion();
                         bail!("")
                     }
                 }
 
             }
+            OpType::Keyword(KeywordType::Break | KeywordType::Continue) => {
+                let block_ip = stack.iter().rev().copied()
+                    .find(|&i| matches!(program[i].typ, OpType::Keyword(KeywordType::While | KeywordType::Do)) && !elifs.contains_key(&i));
+                let Some(do_ip) = block_ip.filter(|&i| program[i].typ == OpType::Keyword(KeywordType::Do)) else {
+                    lerror!(&op.loc, "'{}' can only be used in the body of a while loop", op.typ.human());
+                    op.note_expansion();
+                    bail!("Cross referencing")
+                };
+
+                if op.typ == OpType::Keyword(KeywordType::Continue) {
+                    // until the loops 'end' is found 'do' points back to the 'while'
+                    program[ip].jmp = program[do_ip].jmp;
+                } else {
+                    breaks.push((do_ip, ip));
+                }
+            }
             OpType::Keyword(KeywordType::Do) => {
                 let Some(block_ip) = stack.pop() else {
                     lerror!(&op.loc, "Unclosed while-do block");
+                    op.note_expansion();
                     bail!("Cross referencing")
                 };
 
+                // the 'do' of an 'elif' works like an 'if'
+                if let Some(chain) = elifs.remove(&block_ip) {
+                    elifs.insert(ip, chain);
+                }
                 program[ip].jmp = block_ip;
                 stack.push(ip);
             }
@@ -71,6 +199,7 @@ pub fn cross_ref(mut program: Vec<Operator>) -> Result<Vec<Operator>> {
         // println!("{:?}", stack);
         let i = stack.pop().expect("Empy stack");
         lerror!(&program[i].clone().loc,"Unclosed block, {:?}", program[i].clone());
+        program[i].note_expansion();
         bail!("Unclosed block")
     }
 
@@ -98,13 +227,23 @@ impl<'a> Parser<'a> {
     }
 
     pub fn parse(&mut self) -> Result<Program> {
+        self.preprocessor.program.ops = self.ops()?;
+        let mut t = self.preprocessor.preprocess()?.get_program();
+        t.ops = cross_ref(t.ops)?;
+
+        Ok(t)
+    }
+
+    /// Turns the tokens into operators without preprocessing them
+    pub fn ops(&self) -> Result<Vec<Operator>> {
         let mut tokens = Vec::new();
 
         for token in &self.tokens {
-            if token.text.is_empty() {
+            // empty doc comment lines separate paragraphs
+            if token.text.is_empty() && token.typ != TokenType::DocComment {
                 continue;
             }
-            let pos = (token.file.clone(), token.line, token.col);
+            let pos = token.loc();
             match token.typ {
                 TokenType::Word => {
                     let word_type = if token.op_typ == OpType::Instruction(InstructionType::MemUse) {
@@ -113,42 +252,51 @@ impl<'a> Parser<'a> {
                         lookup_word(&token.text, &pos)
                     };
 
-                    tokens.push(Operator::new(word_type, token.typ, token.value.unwrap_or(0), token.text.clone(), token.file.clone(), token.line, token.col).set_addr(token.addr.unwrap_or(0)));
+                    tokens.push(Operator::new(word_type, token.typ, token.value.unwrap_or(0), token.text.clone(), token.span.file.clone(), token.span.ln, token.span.col).set_addr(token.addr.unwrap_or(0)));
                 },
-                TokenType::Int => {// negative numbers not yet implemented
-                    tokens.push(Operator::new(OpType::Instruction(InstructionType::PushInt), token.typ, token.text.parse::<usize>()?, String::new(), token.file.clone(), token.line, token.col));
+                TokenType::Int => {
+                    let n = match lexer::parse_int(&token.text) {
+                        Ok(n) => n,
+                        Err(e) => {
+                            lerror!(&token.loc(), "{e}");
+                            bail!("")
+                        }
+                    };
+                    // the text tells the typechecker if the literal is signed
+                    tokens.push(Operator::new(OpType::Instruction(InstructionType::PushInt), token.typ, n, token.text.clone(), token.span.file.clone(), token.span.ln, token.span.col));
                 },
                 TokenType::String => {
-                    tokens.push(Operator::new(OpType::Instruction(InstructionType::PushStr), token.typ, 0, token.text.clone(), token.file.clone(), token.line, token.col));
+                    tokens.push(Operator::new(OpType::Instruction(InstructionType::PushStr), token.typ, 0, token.text.clone(), token.span.file.clone(), token.span.ln, token.span.col));
                 },
                 TokenType::CString => {
-                    tokens.push(Operator::new(OpType::Instruction(InstructionType::PushCStr), token.typ, 0, token.text.clone(), token.file.clone(), token.line, token.col));
+                    tokens.push(Operator::new(OpType::Instruction(InstructionType::PushCStr), token.typ, 0, token.text.clone(), token.span.file.clone(), token.span.ln, token.span.col));
+                },
+                TokenType::DocComment => {
+                    tokens.push(Operator::new(OpType::Internal(InternalType::DocComment), token.typ, 0, token.text.clone(), token.span.file.clone(), token.span.ln, token.span.col));
+                },
+                TokenType::Asm => {
+                    tokens.push(Operator::new(OpType::Instruction(InstructionType::InlineAsm), token.typ, 0, token.text.clone(), token.span.file.clone(), token.span.ln, token.span.col));
                 },
                 TokenType::Char => {
-                    let c = token.text.clone();
-                    if c.len() != 1 {
-                        lerror!(&token.loc(), "Chars can only be of lenght 1, got {}", c.len());
+                    let c = token.text.chars().count();
+                    if c != 1 {
+                        lerror!(&token.loc(), "Chars can only be of lenght 1, got {c}");
                         bail!("")
                     }
 
-                    tokens.push(Operator::new(OpType::Instruction(InstructionType::PushInt), token.typ, token.text.chars().next().unwrap() as usize, String::new(), token.file.clone(), token.line, token.col));
+                    tokens.push(Operator::new(OpType::Instruction(InstructionType::PushInt), token.typ, token.text.chars().next().unwrap() as usize, String::new(), token.span.file.clone(), token.span.ln, token.span.col));
                 }
             };
 
 
         }
-        self.preprocessor.program.ops = tokens;
-        let mut t = self.preprocessor.preprocess()?.get_program();
-        t.ops = cross_ref(t.ops)?;
-
-        Ok(t)
+        Ok(tokens)
     }
 }
 
 
 pub fn lookup_word<P: Deref<Target = Loc>>(s: &str, _pos: P) -> OpType {
-    let n = s.parse::<usize>();
-    if n.is_ok() {
+    if lexer::is_int_literal(s) {
         return OpType::Instruction(InstructionType::PushInt);
     }
     match s {
@@ -185,6 +333,11 @@ pub fn lookup_word<P: Deref<Target = Loc>>(s: &str, _pos: P) -> OpType {
         "write32" => OpType::Instruction(InstructionType::Write32),
         "read64" => OpType::Instruction(InstructionType::Read64),
         "write64" => OpType::Instruction(InstructionType::Write64),
+        "[]" => OpType::Instruction(InstructionType::Index),
+        "[]@" => OpType::Instruction(InstructionType::IndexRead),
+        "[]!" => OpType::Instruction(InstructionType::IndexWrite),
+        "@" => OpType::Instruction(InstructionType::FieldRead),
+        "!" => OpType::Instruction(InstructionType::FieldWrite),
         
         "syscall0" => OpType::Instruction(InstructionType::Syscall0),
         "syscall1" => OpType::Instruction(InstructionType::Syscall1),
@@ -196,13 +349,21 @@ pub fn lookup_word<P: Deref<Target = Loc>>(s: &str, _pos: P) -> OpType {
         "cast(bool)" => OpType::Instruction(InstructionType::CastBool),
         "cast(ptr)" => OpType::Instruction(InstructionType::CastPtr),
         "cast(int)" => OpType::Instruction(InstructionType::CastInt),
+        "cast(u8)" | "cast(u16)" | "cast(u32)" | "cast(u64)" |
+        "cast(i8)" | "cast(i16)" | "cast(i32)" | "cast(i64)" => OpType::Instruction(InstructionType::CastSized),
         "cast(void)" => OpType::Instruction(InstructionType::CastVoid),
         // block
         "if" => OpType::Keyword(KeywordType::If),
         "else" => OpType::Keyword(KeywordType::Else),
+        "elif" => OpType::Keyword(KeywordType::Elif),
         "end" => OpType::Keyword(KeywordType::End),
         "while" => OpType::Keyword(KeywordType::While),
         "do" => OpType::Keyword(KeywordType::Do),
+        "break" => OpType::Keyword(KeywordType::Break),
+        "continue" => OpType::Keyword(KeywordType::Continue),
+        "match" => OpType::Keyword(KeywordType::Match),
+        "case" => OpType::Keyword(KeywordType::Case),
+        "default" => OpType::Keyword(KeywordType::Default),
         "include" => OpType::Keyword(KeywordType::Include),
         "memory" => OpType::Keyword(KeywordType::Memory),
         "const" => OpType::Keyword(KeywordType::Constant),
@@ -212,10 +373,22 @@ pub fn lookup_word<P: Deref<Target = Loc>>(s: &str, _pos: P) -> OpType {
         "inline" => OpType::Keyword(KeywordType::Inline),
         "export" => OpType::Keyword(KeywordType::Export),
         "struct" => OpType::Keyword(KeywordType::Struct),
+        "alloc" => OpType::Keyword(KeywordType::Alloc),
+        "asm" => OpType::Keyword(KeywordType::Asm),
+        "macro" => OpType::Keyword(KeywordType::Macro),
+        "#if" => OpType::Keyword(KeywordType::CondIf),
+        "#then" => OpType::Keyword(KeywordType::CondThen),
+        "#else" => OpType::Keyword(KeywordType::CondElse),
+        "#end" => OpType::Keyword(KeywordType::CondEnd),
+        "priv" => OpType::Keyword(KeywordType::Priv),
+        "enum" => OpType::Keyword(KeywordType::Enum),
         "return" => OpType::Instruction(InstructionType::Return),
+        "call" => OpType::Instruction(InstructionType::Call),
         "returns" => OpType::Instruction(InstructionType::Returns),
         "bool" => OpType::Instruction(InstructionType::TypeBool),
         "int" => OpType::Instruction(InstructionType::TypeInt),
+        "u8" | "u16" | "u32" | "u64" |
+        "i8" | "i16" | "i32" | "i64" => OpType::Instruction(InstructionType::TypeSized),
         "ptr" => OpType::Instruction(InstructionType::TypePtr),
         "void" => OpType::Instruction(InstructionType::TypeVoid),
         "any" => OpType::Instruction(InstructionType::TypeAny),
@@ -10,7 +10,17 @@ fn stack_pop(stack: &mut Vec<usize>, loc: &Loc) -> Result<usize> {
     }
 }
 
-pub fn precompile(tokens: &Vec<Operator>) -> Result<Vec<usize>>{
+/// The result of an op that can fail, `None` is reported as `msg` at `token`
+fn checked(v: Option<usize>, token: &Operator, msg: &str) -> Result<usize> {
+    if let Some(v) = v { Ok(v) } else {
+        lerror!(&token.loc, "{msg}");
+        token.note_expansion();
+        bail!("")
+    }
+}
+
+/// Negative ints are in two's complement, so `+`, `-` and `*` wrap like they do at runtime
+pub fn precompile(tokens: &[Operator]) -> Result<Vec<usize>>{
 
     let mut stack: Vec<usize> = Vec::new();
     for token in tokens.iter() {
@@ -24,12 +34,12 @@ pub fn precompile(tokens: &Vec<Operator>) -> Result<Vec<usize>>{
                     InstructionType::Plus => {
                         let a = stack_pop(&mut stack, &loc)?;
                         let b = stack_pop(&mut stack, &loc)?;
-                        stack.push(b + a);
+                        stack.push(b.wrapping_add(a));
                     },
                     InstructionType::Minus => {
-                    let a = stack_pop(&mut stack, &loc)?;
-                    let b = stack_pop(&mut stack, &loc)?;
-                    stack.push(b - a);
+                        let a = stack_pop(&mut stack, &loc)?;
+                        let b = stack_pop(&mut stack, &loc)?;
+                        stack.push(b.wrapping_sub(a));
                     },
                     InstructionType::Equals => {
                         let a = stack_pop(&mut stack, &loc)?;
@@ -77,25 +87,27 @@ pub fn precompile(tokens: &Vec<Operator>) -> Result<Vec<usize>>{
                     InstructionType::Shr => {
                         let a = stack_pop(&mut stack, &loc)?;
                         let b = stack_pop(&mut stack, &loc)?;
-                        stack.push(b >> a);
+                        let shift = u32::try_from(a).ok().and_then(|a| b.checked_shr(a));
+                        stack.push(checked(shift, token, &format!("Cannot shift by {a}, it has to be less than 64"))?);
                     }
                     
                     InstructionType::Shl => {
                         let a = stack_pop(&mut stack, &loc)?;
                         let b = stack_pop(&mut stack, &loc)?;
-                        stack.push(b << a);
+                        let shift = u32::try_from(a).ok().and_then(|a| b.checked_shl(a));
+                        stack.push(checked(shift, token, &format!("Cannot shift by {a}, it has to be less than 64"))?);
                     }
                     
                     InstructionType::DivMod => {
                         let a = stack_pop(&mut stack, &loc)?;
                         let b = stack_pop(&mut stack, &loc)?;
-                        stack.push(b / a);
-                        stack.push(b % a);
+                        stack.push(checked(b.checked_div(a), token, "Division by zero")?);
+                        stack.push(checked(b.checked_rem(a), token, "Division by zero")?);
                     }
                     InstructionType::Mul => {
                         let a = stack_pop(&mut stack, &loc)?;
                         let b = stack_pop(&mut stack, &loc)?;
-                        stack.push(b * a);
+                        stack.push(b.wrapping_mul(a));
                     }
                     InstructionType::Drop => {
                         stack.pop();
@@ -129,14 +141,14 @@ pub fn precompile(tokens: &Vec<Operator>) -> Result<Vec<usize>>{
                     }
                     _ => {
                         lerror!(&token.loc, "Unsupported precompiler instruction {:?}", i);
-                        dbg!(tokens);
+                        token.note_expansion();
                         bail!("");
                     }
                 }
             }
             OpType::Keyword(_) => {
                 lerror!(&token.loc, "Unsupported precompiler keyword {:?}", token.typ);
-                dbg!(tokens);
+                token.note_expansion();
                 bail!("");
             }
             OpType::Internal(t) => panic!("{t:?}"),
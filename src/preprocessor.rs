@@ -1,25 +1,79 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::path::{PathBuf, Path};
+use std::rc::Rc;
 
 
 use anyhow::{Result, bail};
 
 use crate::definitions::*;
-use crate::lexer::lex;
+use crate::lexer::{lex, is_int_literal, parse_int};
 use crate::precompiler::precompile;
-use crate::{lerror, Args, warn, linfo, parser};
+use crate::{lerror, Args, linfo, lwarn, parser};
 use crate::parser::lookup_word;
 
 
 
 
-#[derive(Debug, Clone)]
+/// What an included file defines, under the names it defines them as
+#[derive(Debug, Clone, Default)]
+struct Module {
+    functions: Functions,
+    constants: Constants,
+    memories: Memories,
+    macros: Macros,
+    enums: Enums,
+    struct_defs: StructDefs,
+}
+
+/// The names a preprocessor had defined when it included a file
+struct Known {
+    functions: HashSet<String>,
+    constants: HashSet<String>,
+    memories: HashSet<String>,
+    macros: HashSet<String>,
+    enums: HashSet<String>,
+    struct_defs: HashSet<String>,
+}
+
+impl Known {
+    fn of(program: &Program) -> Self {
+        fn keys<T>(m: &HashMap<String, T>) -> HashSet<String> {
+            m.keys().cloned().collect()
+        }
+        Self {
+            functions: keys(&program.functions),
+            constants: keys(&program.constants),
+            memories: keys(&program.memories),
+            macros: keys(&program.macros),
+            enums: keys(&program.enums),
+            struct_defs: keys(&program.struct_defs),
+        }
+    }
+}
+
+/// Include state of the whole compilation, shared by the preprocessors of all files
+#[derive(Debug, Default)]
+struct Includes {
+    // canonical paths of the files being included right now, outermost first, and where they were included
+    stack: Vec<(PathBuf, Loc)>,
+    // files that were included already
+    done: HashMap<PathBuf, Module>,
+}
+
+#[derive(Debug)]
 pub struct Preprocessor<'a> {
     pub program: Program,
     in_function: Option<String>,
     args: &'a Args,
     f_inline: bool,
     f_export: bool,
+    f_priv: bool,
+    // doc comment for the next definition
+    doc: Option<String>,
+    // definitions marked 'priv', files that include this one cant see them
+    privates: Vec<String>,
+    includes: Rc<RefCell<Includes>>,
 }
 
 
@@ -33,11 +87,16 @@ impl<'a> Preprocessor<'a> {
                 memories: HashMap::new(),
                 constants: HashMap::new(),
                 struct_defs: HashMap::new(),
-                struct_allocs: HashMap::new()
+                macros: HashMap::new(),
+                enums: HashMap::new(),
             },
             in_function: None,
             f_inline: false,
             f_export: false,
+            f_priv: false,
+            doc: None,
+            privates: Vec::new(),
+            includes: Rc::default(),
         }
     }
 
@@ -48,18 +107,54 @@ impl<'a> Preprocessor<'a> {
 
         let mut program: Vec<Operator> = Vec::new();
 
-        let mut rtokens = self.program.ops.clone();
+        // taken so included files and inline functions dont copy all of it
+        let mut rtokens = std::mem::take(&mut self.program.ops);
         rtokens.reverse();
-        while !rtokens.is_empty() {
-            let mut op = rtokens.pop().unwrap();
+        while let Some(mut op) = rtokens.pop() {
             // println!("{token:?}");
             let op_type = op.typ.clone();
+            if self.f_priv && !matches!(op_type,
+                    OpType::Keyword(KeywordType::Function | KeywordType::Constant | KeywordType::Memory |
+                        KeywordType::Macro | KeywordType::Enum | KeywordType::Struct | KeywordType::Alloc |
+                        KeywordType::Inline | KeywordType::Export | KeywordType::Priv) |
+                    OpType::Internal(InternalType::DocComment)) {
+                lerror!(&op.loc, "Expected a definition after 'priv', got {}", op.typ.human());
+                bail!("");
+            }
             match op_type {
-                OpType::Keyword(KeywordType::Include) => self.handle_include(&mut rtokens, &mut op)?,
+                OpType::Keyword(KeywordType::Include) => {
+                    self.doc = None;
+                    self.handle_include(&mut rtokens, &mut op, &mut program)?;
+                }
                 OpType::Keyword(KeywordType::Memory) => self.handle_memory(&mut rtokens, &mut op, &mut program)?,
                 OpType::Keyword(KeywordType::Function) => self.handle_function(&mut rtokens, &mut op, &mut program)?,
                 OpType::Keyword(KeywordType::Constant) => self.handle_constant(&mut rtokens, &mut op, &mut program)?,  
-                OpType::Keyword(KeywordType::Struct) => self.handle_struct(&mut rtokens, &mut op, &mut program)?,  
+                OpType::Keyword(KeywordType::Enum) => self.handle_enum(&mut rtokens, &mut op, &mut program)?,
+                OpType::Keyword(KeywordType::Struct) => {
+                    self.doc = None;
+                    self.handle_struct(&mut rtokens, &mut op)?;
+                }
+                OpType::Keyword(KeywordType::Alloc) => self.handle_alloc(&mut rtokens, &mut op, &mut program)?,
+                OpType::Keyword(KeywordType::Asm) => {
+                    self.doc = None;
+                    self.handle_asm(&mut rtokens, &mut op, &mut program)?;
+                }
+                OpType::Keyword(KeywordType::Macro) => {
+                    self.doc = None;
+                    self.handle_macro(&mut rtokens, &op)?;
+                }
+                OpType::Keyword(KeywordType::CondIf) => self.handle_cond(&mut rtokens, &op)?,
+                OpType::Keyword(KeywordType::Case | KeywordType::Default) => self.handle_case(&mut rtokens, &mut op, &mut program)?,
+                OpType::Keyword(KeywordType::CondThen | KeywordType::CondElse | KeywordType::CondEnd) => {
+                    lerror!(&op.loc, "'{}' without an '#if'", op.typ.human());
+                    bail!("");
+                }
+                OpType::Instruction(InstructionType::None) if self.program.macros.contains_key(&op.text) => {
+                    // the body goes back in front so it gets preprocessed as if it was written here
+                    let mut body = self.expand_macro(&op)?;
+                    body.reverse();
+                    rtokens.append(&mut body);
+                }
                 OpType::Keyword(KeywordType::Inline) => {
                     if self.f_export {
                         lerror!(&op.loc, "Function is already marked as exported, function cannot be inline and exported at the same time");
@@ -72,6 +167,14 @@ impl<'a> Preprocessor<'a> {
                     }
                 }
 
+                OpType::Keyword(KeywordType::Priv) => {
+                    if self.f_priv {
+                        lerror!(&op.loc, "Definition is already marked as private, remove this priv Keyword");
+                        bail!("");
+                    }
+                    self.f_priv = true;
+                }
+
                 OpType::Keyword(KeywordType::Export) => {
                     if !crate::config::ENABLE_EXPORTED_FUNCTIONS {
                         lerror!(&op.loc, "Experimental feature Exported functions not enabled");
@@ -88,50 +191,37 @@ impl<'a> Preprocessor<'a> {
                     }
                 }
 
-                _ => {
-                    program.push(op);
-                }
-            }
-        }
-        self.program.ops = program;
-        // println!("has do tokens: {:?}", self.program.iter().map(|t| if t.typ == OpType::Keyword(KeywordType::Do) {Some(t)} else {None} ).collect::<Vec<Option<&Operator>>>());
-        //* Feel free to fix this horrifying shit
-        //* i wanna kms
-        let mut times = 0;
-        // dbg!(program.clone());
-        while self.program.ops.iter().map(|f| {
-            if f.tok_typ == TokenType::Word {
-                match f.typ {
-                    OpType::Instruction(InstructionType::FnCall)     |
-                    OpType::Instruction(InstructionType::MemUse)     |
-                    OpType::Instruction(InstructionType::StructUse)  |
-                    OpType::Keyword(KeywordType::FunctionDef)        |
-                    OpType::Keyword(KeywordType::FunctionDefExported)|
-                    OpType::Keyword(KeywordType::ConstantDef)        |
-                    OpType::Internal(InternalType::StructAlloc{..})  |
-                    OpType::Instruction(InstructionType::ConstUse) => OpType::Instruction(InstructionType::PushInt),
-                    _ => {
-                        lookup_word(&f.text, &f.loc)
+                OpType::Internal(InternalType::DocComment) => {
+                    // consecutive doc comment lines are one comment
+                    match &mut self.doc {
+                        Some(doc) => {
+                            doc.push('\n');
+                            doc.push_str(&op.text);
+                        }
+                        None => self.doc = Some(op.text)
                     }
                 }
-            } else {
-                OpType::Instruction(InstructionType::PushInt) // i hate myself, this is a randomly picked optype so its happy and works
-            }
 
-        }).collect::<Vec<OpType>>().contains(&OpType::Instruction(InstructionType::None)){
+                OpType::Keyword(KeywordType::FunctionDone) => {
+                    self.in_function = None;
+                    self.doc = None;
+                    program.push(op);
+                }
 
-            if times >= 50 {
-                warn!("File import depth maxed out, if the program crashes try reducing the import depth, good luck youll need it");
-                break
+                _ => {
+                    self.doc = None;
+                    program.push(op);
+                }
             }
-            self.expand()?;
-            times += 1;
         }
+        self.program.ops = program;
+        // words are resolved or reported as unknown in one pass, included files come in resolved already
+        self.expand()?;
         Ok(self)
     }
 
 
-    fn handle_include(&mut self, rtokens: &mut Vec<Operator>, op: &mut Operator) -> Result<()> {
+    fn handle_include(&mut self, rtokens: &mut Vec<Operator>, op: &mut Operator, program: &mut Vec<Operator>) -> Result<()> {
         if rtokens.is_empty() {
             lerror!(&op.loc, "Include path not found, expected {} but found nothing", TokenType::String.human());
             bail!("");
@@ -144,45 +234,326 @@ impl<'a> Preprocessor<'a> {
             bail!("");
         }
 
+        // include "file.mcl" as name
+        let mut alias = None;
+        if rtokens.last().is_some_and(|t| t.tok_typ == TokenType::Word && t.text == "as") {
+            let as_op = rtokens.pop().unwrap();
+            let Some(name) = rtokens.pop() else {
+                lerror!(&as_op.loc, "Namespace name not found, expected {} but found nothing", TokenType::Word.human());
+                bail!("");
+            };
+            if name.tok_typ != TokenType::Word || lookup_word(&name.text, &name.loc) != OpType::Instruction(InstructionType::None) ||
+                !name.text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                lerror!(&name.loc, "Bad namespace name {:?}, it can only contain letters, numbers and '_'", name.text);
+                bail!("");
+            }
+            alias = Some(name.text);
+        }
+
         let mut in_paths = self.args.include.clone();
         in_paths.append(&mut crate::DEFAULT_INCLUDES.to_vec().clone().iter().map(|f| (*f).to_string()).collect::<Vec<String>>());
         
-        let mut include_code = String::new();
-        let mut pth = PathBuf::new();
-        if include_path.text.chars().next().unwrap() == '.' {
+        let mut pth = None;
+        if include_path.text.starts_with('.') {
             let p = Path::new(include_path.loc.0.as_str());
             let p = p.parent().unwrap();
-            let p = p.join(&include_path.text);
-            pth = p.clone();
-            include_code = std::fs::read_to_string(p)?;
+            pth = Some(p.join(&include_path.text));
         } else {   
             for path in in_paths {
                 let p = PathBuf::from(path);
                 let p = p.join(&include_path.text);
-                pth = p.clone();
                 
                 if p.exists() {
-                    include_code = std::fs::read_to_string(p)?;
+                    pth = Some(p);
                     break;
                 }
                 
             }
         }
 
-        if include_code.is_empty() {
-            lerror!(&include_path.loc, "Include file in path '{}' was not found or is empty", include_path.text);
+        // the same file can be reached through different paths, so files are told apart by their canonical path
+        let Some((pth, key)) = pth.and_then(|p| p.canonicalize().ok().map(|k| (p, k))) else {
+            lerror!(&include_path.loc, "Include file in path '{}' was not found", include_path.text);
             bail!("");
+        };
+
+        let cached = self.includes.borrow().done.get(&key).cloned();
+        if let Some(module) = cached {
+            // every file is only included once, later includes only make its definitions visible
+            return self.bind(&module, alias.as_deref());
+        }
+
+        {
+            let mut includes = self.includes.borrow_mut();
+            if includes.stack.is_empty() {
+                if let Ok(root) = Path::new(&op.loc.0).canonicalize() {
+                    includes.stack.push((root, op.loc.clone()));
+                }
+            }
+            if let Some(i) = includes.stack.iter().position(|(p, _)| *p == key) {
+                let chain = includes.stack[i..].iter().map(|(p, _)| p.display().to_string())
+                    .chain([key.display().to_string()])
+                    .collect::<Vec<String>>();
+                lerror!(&include_path.loc, "Include cycle: {}", chain.join(" -> "));
+                for (p, loc) in includes.stack[i + 1..].iter().rev() {
+                    linfo!(loc, "'{}' is included here", p.display());
+                }
+                bail!("");
+            }
+            includes.stack.push((key.clone(), include_path.loc.clone()));
         }
+
         let a = pth.to_str().unwrap().to_string();
-        let code = lex(&include_code, a.as_str(), self.args);
-        let mut p = parser::Parser::new(code, self.args, Some(self.clone()));
-        let mut code = p.parse()?;
-
-        self.set_constants(p.preprocessor.get_constants());
-        self.set_functions(p.preprocessor.get_functions());
-        self.set_memories(p.preprocessor.get_memories());
-        code.ops.reverse();
-        rtokens.append(&mut code.ops);
+        let include_code = std::fs::read_to_string(&pth)?;
+        let code = lex(&include_code, a.as_str(), self.args)?;
+        // the included file sees our definitions, they are handed to it and come back with the new ones
+        let known = Known::of(&self.program);
+        let mut p = parser::Parser::new(code, self.args, Some(self.child()));
+        let code = p.parse();
+        self.includes.borrow_mut().stack.pop();
+        let mut code = code?;
+
+        let module = self.import(&mut code, &known, &p.preprocessor.privates, &a, alias.as_deref())?;
+        self.bind(&module, alias.as_deref())?;
+        self.includes.borrow_mut().done.insert(key, module);
+        // the included file is preprocessed already
+        program.append(&mut code.ops);
+        Ok(())
+    }
+
+    /// Preprocessor for an included file, it takes over everything defined so far
+    fn child(&mut self) -> Self {
+        Self {
+            args: self.args,
+            program: Program {
+                ops: Vec::new(),
+                functions: std::mem::take(&mut self.program.functions),
+                memories: std::mem::take(&mut self.program.memories),
+                constants: std::mem::take(&mut self.program.constants),
+                struct_defs: std::mem::take(&mut self.program.struct_defs),
+                macros: std::mem::take(&mut self.program.macros),
+                enums: std::mem::take(&mut self.program.enums),
+            },
+            in_function: None,
+            f_inline: false,
+            f_export: false,
+            f_priv: false,
+            doc: None,
+            privates: Vec::new(),
+            includes: Rc::clone(&self.includes),
+        }
+    }
+
+    /// Takes back the definitions handed to the preprocessor of the included `file`, the `known`
+    /// ones, and returns what `file` offers under the names they have there. With an `alias` the
+    /// definitions made in `file` get internal names of their own, the ones marked 'priv' get a
+    /// key no word can refer to
+    fn import(&mut self, defs: &mut Program, known: &Known, privates: &[String], file: &str, alias: Option<&str>) -> Result<Module> {
+        let file_id = crate::types::common::Loc::file_id(file);
+        let hidden = |key: &str| format!("__PRIV{file_id}__{key}");
+        // internal name of a definition offered by `file`, if it changes
+        let internal = |key: &str, loc: &Loc| alias.filter(|_| loc.0 == file).map(|a| format!("{a}__NS__{key}"));
+
+        let mut module = Module::default();
+        // the ops of the included file are already resolved, so the names they use change too
+        let mut renames: HashMap<String, String> = HashMap::new();
+
+        for (key, mut f) in std::mem::take(&mut defs.functions) {
+            if known.functions.contains(&key) {
+                self.program.functions.insert(key, f);
+                continue;
+            }
+            if privates.contains(&key) {
+                renames.insert(f.name.clone(), hidden(&key));
+                f.name = hidden(&key);
+                self.program.functions.insert(hidden(&key), f);
+            } else if key.starts_with("__PRIV") {
+                self.program.functions.insert(key, f);
+            } else {
+                if let Some(name) = internal(&key, &f.loc) {
+                    renames.insert(f.name.clone(), name.clone());
+                    f.name = name;
+                }
+                module.functions.insert(key, f);
+            }
+        }
+        for (key, mut c) in std::mem::take(&mut defs.constants) {
+            if known.constants.contains(&key) {
+                self.program.constants.insert(key, c);
+                continue;
+            }
+            if privates.contains(&key) {
+                renames.insert(c.name.clone(), hidden(&key));
+                c.name = hidden(&key);
+                self.program.constants.insert(hidden(&key), c);
+            } else if key.starts_with("__PRIV") {
+                self.program.constants.insert(key, c);
+            } else {
+                if let Some(name) = internal(&key, &c.loc) {
+                    renames.insert(c.name.clone(), name.clone());
+                    c.name = name;
+                }
+                module.constants.insert(key, c);
+            }
+        }
+        for (key, m) in std::mem::take(&mut defs.memories) {
+            if known.memories.contains(&key) {
+                self.program.memories.insert(key, m);
+                continue;
+            }
+            if privates.contains(&key) {
+                self.program.memories.insert(hidden(&key), m);
+            } else if key.starts_with("__PRIV") {
+                self.program.memories.insert(key, m);
+            } else {
+                module.memories.insert(key, m);
+            }
+        }
+        for (key, mut e) in std::mem::take(&mut defs.enums) {
+            if known.enums.contains(&key) {
+                self.program.enums.insert(key, e);
+                continue;
+            }
+            if privates.contains(&key) {
+                renames.insert(e.name.clone(), hidden(&key));
+                e.name = hidden(&key);
+                self.program.enums.insert(hidden(&key), e);
+            } else if key.starts_with("__PRIV") {
+                self.program.enums.insert(key, e);
+            } else {
+                if let Some(name) = internal(&key, &e.loc) {
+                    renames.insert(e.name.clone(), name.clone());
+                    e.name = name;
+                }
+                module.enums.insert(key, e);
+            }
+        }
+        for (key, mut s) in std::mem::take(&mut defs.struct_defs) {
+            if known.struct_defs.contains(&key) {
+                self.program.struct_defs.insert(key, s);
+                continue;
+            }
+            if privates.contains(&key) {
+                renames.insert(s.name.clone(), hidden(&key));
+                s.name = hidden(&key);
+                self.program.struct_defs.insert(hidden(&key), s);
+            } else if key.starts_with("__PRIV") {
+                self.program.struct_defs.insert(key, s);
+            } else {
+                if let Some(name) = internal(&key, &s.loc) {
+                    renames.insert(s.name.clone(), name.clone());
+                    s.name = name;
+                }
+                module.struct_defs.insert(key, s);
+            }
+        }
+        for (key, m) in std::mem::take(&mut defs.macros) {
+            if known.macros.contains(&key) {
+                self.program.macros.insert(key, m);
+            // private macros were already expanded where they were used
+            } else if !privates.contains(&key) && !key.starts_with("__PRIV") {
+                module.macros.insert(key, m);
+            }
+        }
+
+        let rename = |ops: &mut [Operator]| {
+            for op in ops {
+                match op.typ {
+                    OpType::Keyword(KeywordType::FunctionDef | KeywordType::FunctionDefExported | KeywordType::ConstantDef) |
+                    OpType::Instruction(InstructionType::FnCall | InstructionType::FnAddr | InstructionType::ConstUse | InstructionType::TypeEnum |
+                        InstructionType::CastEnum | InstructionType::TypeStruct | InstructionType::CastStruct | InstructionType::Field) => (),
+                    // every enum and struct in the signature, structs have a prefix and arrays a suffix
+                    OpType::Instruction(InstructionType::TypeFn | InstructionType::CastFn | InstructionType::TypeArray | InstructionType::CastArray) => {
+                        op.text = op.text.split(' ').map(|w| {
+                            let (kind, name) = w.split_once(':').map_or(("", w), |(k, n)| (&w[..=k.len()], n));
+                            let (name, count) = name.split_once('[').map_or((name, ""), |(n, _)| (n, &name[n.len()..]));
+                            format!("{kind}{}{count}", renames.get(name).map_or(name, |n| n.as_str()))
+                        }).collect::<Vec<String>>().join(" ");
+                        continue;
+                    }
+                    _ => continue
+                }
+                if let Some(n) = renames.get(&op.text) {
+                    op.text.clone_from(n);
+                }
+            }
+        };
+        let rename_array = |m: &mut Memory| {
+            if let Some(n) = m.array.as_mut().and_then(|(elem, _)| renames.get(elem.as_str())) {
+                m.array.as_mut().unwrap().0.clone_from(n);
+            }
+            if let Some(n) = m.instance.as_ref().and_then(|s| renames.get(s)) {
+                m.instance = Some(n.clone());
+            }
+        };
+        for s in module.struct_defs.values_mut().chain(self.program.struct_defs.values_mut()) {
+            for f in &mut s.fields {
+                if let Types::Enum(n) | Types::Struct(n) = &mut f.typ {
+                    if let Some(r) = renames.get(n.as_str()) {
+                        n.clone_from(r);
+                    }
+                }
+            }
+        }
+        rename(&mut defs.ops);
+        for f in module.functions.values_mut().chain(self.program.functions.values_mut()) {
+            if let Some(tokens) = &mut f.tokens {
+                rename(tokens);
+            }
+            f.memories.values_mut().for_each(rename_array);
+        }
+        module.memories.values_mut().chain(self.program.memories.values_mut()).for_each(rename_array);
+        Ok(module)
+    }
+
+    /// Makes the definitions of an included file visible, as `alias::name` if there is an alias
+    fn bind(&mut self, module: &Module, alias: Option<&str>) -> Result<()> {
+        let key = |k: &str| alias.map_or(k.to_string(), |a| format!("{a}::{k}"));
+        let word = |key: &str, loc: &Loc| Operator::new(OpType::Instruction(InstructionType::None), TokenType::Word, 0, key.to_string(), loc.0.clone(), loc.1, loc.2);
+
+        // the same definition can be visible under a name already, when a file is included again
+        for (k, f) in &module.functions {
+            if self.program.functions.get(&key(k)).is_some_and(|g| g.loc == f.loc) {
+                continue;
+            }
+            self.is_word_available(&word(&key(k), &f.loc), KeywordType::Function)?;
+            self.program.functions.insert(key(k), f.clone());
+        }
+        for (k, c) in &module.constants {
+            if self.program.constants.get(&key(k)).is_some_and(|d| d.loc == c.loc) {
+                continue;
+            }
+            self.is_word_available(&word(&key(k), &c.loc), KeywordType::Constant)?;
+            self.program.constants.insert(key(k), c.clone());
+        }
+        for (k, m) in &module.memories {
+            if self.program.memories.get(&key(k)).is_some_and(|n| n.loc == m.loc) {
+                continue;
+            }
+            self.is_word_available(&word(&key(k), &m.loc), KeywordType::Memory)?;
+            self.program.memories.insert(key(k), m.clone());
+        }
+        for (k, m) in &module.macros {
+            if self.program.macros.get(&key(k)).is_some_and(|n| n.loc == m.loc) {
+                continue;
+            }
+            self.is_word_available(&word(&key(k), &m.loc), KeywordType::Macro)?;
+            self.program.macros.insert(key(k), m.clone());
+        }
+        for (k, e) in &module.enums {
+            if self.program.enums.get(&key(k)).is_some_and(|f| f.loc == e.loc) {
+                continue;
+            }
+            self.is_word_available(&word(&key(k), &e.loc), KeywordType::Enum)?;
+            self.program.enums.insert(key(k), e.clone());
+        }
+        for (k, s) in &module.struct_defs {
+            if self.program.struct_defs.get(&key(k)).is_some_and(|t| t.loc == s.loc) {
+                continue;
+            }
+            self.is_word_available(&word(&key(k), &s.loc), KeywordType::Struct)?;
+            self.program.struct_defs.insert(key(k), s.clone());
+        }
         Ok(())
     }
 
@@ -193,42 +564,144 @@ impl<'a> Preprocessor<'a> {
         }
 
         let name = rtokens.pop().unwrap();
+        let doc = self.doc.take();
+        let private = std::mem::take(&mut self.f_priv);
 
         self.is_word_available(&name, KeywordType::Memory)?;
 
-        let mut code: Vec<Operator> = Vec::new();
+        let Some(code) = definition_body(rtokens) else {
+            lerror!(&op.loc, "Memory '{}' was not closed with an 'end'", name.text);
+            bail!("");
+        };
+        let array = self.array_spec(&code)?;
+        op.value = match &array {
+            Some((elem, count)) => Types::from_element(elem).get_size() as usize * count,
+            None => self.eval(op, code, false)?
+        };
+        let memory = Memory { loc: op.loc.clone(), id: 0, doc, array, instance: None };
+        self.define_memory(name, memory, private, op, program)
+    }
 
-        let mut depth = 0;
-        while !rtokens.is_empty() {
-            let t = rtokens.pop().unwrap();
-            let typ = t.typ.clone();
-            if typ == OpType::Keyword(KeywordType::End) && depth == 0 {
-                break;
-            } else if typ == OpType::Keyword(KeywordType::End) && depth != 0 {
-                depth -= 1;
-                code.push(t);
-            } else if typ == OpType::Keyword(KeywordType::If) || typ == OpType::Keyword(KeywordType::Do) {
-                code.push(t);
-                depth += 1;
-            } else {
-                code.push(t);
+    /// `alloc Name Struct end`, a memory that is an instance of a struct
+    fn handle_alloc(&mut self, rtokens: &mut Vec<Operator>, op: &mut Operator, program: &mut Vec<Operator>) -> Result<()> {
+        let (Some(name), Some(typ)) = (rtokens.pop(), rtokens.pop()) else {
+            lerror!(&op.loc, "Expected 'alloc Name Struct end', but the file ended");
+            bail!("");
+        };
+        let doc = self.doc.take();
+        let private = std::mem::take(&mut self.f_priv);
+        self.is_word_available(&name, KeywordType::Memory)?;
+
+        let Some(def) = self.program.struct_defs.get(&typ.text) else {
+            lerror!(&typ.loc, "Expected a struct to allocate, got '{}'", typ.text);
+            bail!("");
+        };
+        match rtokens.pop() {
+            Some(t) if t.typ == OpType::Keyword(KeywordType::End) => (),
+            t => {
+                lerror!(&t.as_ref().unwrap_or(&typ).loc, "Expected 'end' after the struct to allocate, got {}", t.as_ref().map_or("nothing".to_string(), |t| t.typ.human()));
+                bail!("");
             }
         }
-        let res = precompile(&code)?;
 
+        // from here on an instance is just a memory of the structs size
+        op.typ = OpType::Keyword(KeywordType::Memory);
+        op.value = def.size;
+        let memory = Memory { loc: op.loc.clone(), id: 0, doc, array: None, instance: Some(def.name.clone()) };
+        self.define_memory(name, memory, private, op, program)
+    }
 
-        if res.len() != 1 {
-            lerror!(&op.loc, "Expected 1 number, got {:?}", res);
-            bail!("");
+    /// Adds a memory of `op.value` bytes, a local one if we are in a function
+    fn define_memory(&mut self, name: Operator, mut memory: Memory, private: bool, op: &mut Operator, program: &mut Vec<Operator>) -> Result<()> {
+        // memories declared inside of a function live in that functions call frame
+        if let Some(f) = &self.in_function {
+            // inline functions are only defined after their body is preprocessed
+            let Some(func) = self.program.functions.get_mut(f) else {
+                lerror!(&op.loc, "Memories cannot be declared inside of the inline function '{}', every use of it would share them", f);
+                bail!("");
+            };
+            if private {
+                lerror!(&op.loc, "Memories inside of a function are already private, remove this priv Keyword");
+                bail!("");
+            }
+            if let Some(m) = func.memories.get(&name.text) {
+                lerror!(&name.loc, "Memories cannot be redefined, got {}", name.text);
+                linfo!(&m.loc, "first definition here");
+                bail!("");
+            }
+            memory.id = func.frame_size;
+            func.memories.insert(name.text, memory);
+            // keep the frame 8 byte aligned
+            func.frame_size += op.value.div_ceil(8) * 8;
+            // the frame also holds the return address
+            if 8 + func.frame_size > crate::config::RET_STACK_SZ {
+                lerror!(&op.loc, "The local memories of '{}' need {} bytes, but the return stack only has {}", f, 8 + func.frame_size, crate::config::RET_STACK_SZ);
+                bail!("");
+            }
+            return Ok(());
         }
-        op.value = res[0];
+
         op.addr = Some(self.program.memories.len());
+        op.text.clone_from(&name.text);
         program.push(op.clone());
 
-        self.program.memories.insert(name.text, Memory { loc: op.loc.clone(), id: self.program.memories.len() });
+        if private {
+            self.privates.push(name.text.clone());
+        }
+        memory.id = self.program.memories.len();
+        self.program.memories.insert(name.text, memory);
         Ok(())
     }
 
+    /// A memory body of `<type> [count]` declares an array, the element type is kept by name
+    /// The type `u32[]` stands for, a pointer to u32s
+    fn array_type(&self, word: &str) -> Option<Types> {
+        let elem = word.strip_suffix("[]")?;
+        let elem = match self.program.enums.get(elem) {
+            Some(e) => Types::Enum(e.name.clone()),
+            None => Types::from_string(&elem).ok().filter(|t| t.get_size() > 0)?
+        };
+        Some(Types::PtrTo(Box::new(elem), None))
+    }
+
+    fn array_spec(&self, code: &[Operator]) -> Result<Option<(String, usize)>> {
+        let [elem, count] = code else {
+            return Ok(None);
+        };
+        let Some(count_s) = count.text.strip_prefix('[').and_then(|c| c.strip_suffix(']')) else {
+            return Ok(None);
+        };
+
+        let elem = match self.program.enums.get(&elem.text) {
+            Some(e) => e.name.clone(),
+            None => match Types::from_string(&elem.text) {
+                Ok(t) if t.get_size() > 0 => elem.text.clone(),
+                _ => {
+                    lerror!(&elem.loc, "Unknown array element type '{}'", elem.text);
+                    bail!("");
+                }
+            }
+        };
+
+        // the count is a number or a constant
+        let mut c = count.clone();
+        c.text = count_s.to_string();
+        if is_int_literal(count_s) {
+            c.typ = OpType::Instruction(InstructionType::PushInt);
+            c.tok_typ = TokenType::Int;
+            c.value = match parse_int(count_s) {
+                Ok(v) => v,
+                Err(e) => {
+                    lerror!(&count.loc, "{e}");
+                    bail!("");
+                }
+            };
+        } else {
+            c.typ = lookup_word(count_s, &c.loc);
+        }
+        Ok(Some((elem, self.eval(count, vec![c], false)?)))
+    }
+
     fn handle_function(&mut self, rtokens: &mut Vec<Operator>, op: &mut Operator, program: &mut Vec<Operator>) -> Result<()> {
         if rtokens.is_empty() {
             lerror!(&op.loc, "Function name not found, expected {} but found nothing", TokenType::Word.human());
@@ -236,6 +709,7 @@ impl<'a> Preprocessor<'a> {
         }
 
         let mut name = rtokens.pop().unwrap();
+        let doc = self.doc.take();
 
         if let '0'..='9' = name.text.chars().next().unwrap() {
             lerror!(&name.loc, "Function name starts with a number which is not allowed");
@@ -264,14 +738,15 @@ impl<'a> Preprocessor<'a> {
         // }
 
         self.is_word_available(&name, KeywordType::Function)?;
-        
+        if std::mem::take(&mut self.f_priv) {
+            self.privates.push(name.text.clone());
+        }
         
         if self.f_inline {
             self.f_inline = false;
             let mut prog: Vec<Operator> = Vec::new();
             let mut depth = -1;
-            while !rtokens.is_empty() {
-                let op = rtokens.pop().unwrap();
+            while let Some(op) = rtokens.pop() {
 
                 match op.typ.clone() {
                     OpType::Instruction(i) => {
@@ -279,6 +754,7 @@ impl<'a> Preprocessor<'a> {
                             InstructionType::TypeAny |
                             InstructionType::TypeBool |
                             InstructionType::TypeInt |
+                            InstructionType::TypeSized |
                             InstructionType::TypePtr |
                             InstructionType::With |
                             InstructionType::Returns |
@@ -287,6 +763,8 @@ impl<'a> Preprocessor<'a> {
                                     prog.push(op);
                                 }
                             },
+                            // type variables of the signature
+                            InstructionType::None if depth < 0 && op.text.starts_with('\'') => (),
                             _ => prog.push(op)
                         }
                     }
@@ -308,6 +786,7 @@ impl<'a> Preprocessor<'a> {
                                 }
 
                                 depth -= 1;
+                                prog.push(op);
                             },
                             _ => prog.push(op)
                         }
@@ -315,19 +794,21 @@ impl<'a> Preprocessor<'a> {
                     _ => prog.push(op)
                 }
             }
-            let mut pre = self.clone();
-            pre.program.ops = prog;
-            if name.text.chars().next().unwrap() == '.' {
-                pre.in_function = Some(name.text[1..].to_string());
-            }
-            pre.preprocess()?;
-            prog = pre.get_ops();
+            // the body is preprocessed in place, the ops around it were taken out already
+            let outer = std::mem::replace(&mut self.program.ops, prog);
+            let in_function = self.in_function.replace(name.text.clone());
+            self.preprocess()?;
+            prog = std::mem::replace(&mut self.program.ops, outer);
+            self.in_function = in_function;
 
             self.program.functions.insert(name.text.clone(), Function{
                 loc: name.loc.clone(),
                 name: name.text.clone(),
                 inline: true,
-                tokens: Some(prog)
+                tokens: Some(prog),
+                memories: HashMap::new(),
+                frame_size: 0,
+                doc: doc.clone()
             });
             
         } else if self.f_export {
@@ -336,14 +817,16 @@ impl<'a> Preprocessor<'a> {
                 loc: name.loc.clone(),
                 name: name.text.clone(),
                 inline: false,
-                tokens: None
+                tokens: None,
+                memories: HashMap::new(),
+                frame_size: 0,
+                doc: doc.clone()
             });
             let mut a: Vec<Operator> = Vec::new();
             let mut fn_def = op.clone();
             a.push(rtokens.pop().unwrap());
             let mut ret = false;
-            while !rtokens.is_empty() {
-                let op = rtokens.pop().unwrap();
+            while let Some(op) = rtokens.pop() {
                 // println!("{:?}",op);
                 a.push(op.clone());
                 if op.typ == OpType::Instruction(InstructionType::Returns) {
@@ -356,6 +839,7 @@ impl<'a> Preprocessor<'a> {
 
                 if op.typ == OpType::Instruction(InstructionType::TypeBool) ||
                     op.typ == OpType::Instruction(InstructionType::TypeInt) ||
+                    op.typ == OpType::Instruction(InstructionType::TypeSized) ||
                     op.typ == OpType::Instruction(InstructionType::TypePtr) {
 
                     if ret {
@@ -367,6 +851,7 @@ impl<'a> Preprocessor<'a> {
             }
 
             fn_def.typ = OpType::Keyword(KeywordType::FunctionDefExported);
+            self.in_function = Some(name.text.clone());
             fn_def.text = name.text;
             // fn_def.set_types(args, rets);
             // println!("{:?}", fn_def.types);
@@ -380,9 +865,13 @@ impl<'a> Preprocessor<'a> {
                 loc: name.loc.clone(),
                 name: name.text.clone(),
                 inline: false,
-                tokens: None
+                tokens: None,
+                memories: HashMap::new(),
+                frame_size: 0,
+                doc: doc.clone()
             });
             
+            self.in_function = Some(name.text.clone());
             let mut fn_def = op.clone();
             fn_def.typ = OpType::Keyword(KeywordType::FunctionDef);
             fn_def.text = name.text;
@@ -397,7 +886,8 @@ impl<'a> Preprocessor<'a> {
             lerror!(&op.loc, "Constant name not found, expected {} but found nothing", TokenType::Word.human());
             bail!("");
         };
-        
+        let doc = self.doc.take();
+        let private = std::mem::take(&mut self.f_priv);
 
         if let '0'..='9' | '.' = name.text.chars().next().unwrap() {
             lerror!(&name.loc, "Constant name starts with a number or dot which is not allowed");
@@ -429,47 +919,148 @@ impl<'a> Preprocessor<'a> {
         self.is_word_available(&name, KeywordType::Constant)?;
         
         
+        let mut const_def = op.clone();
+        const_def.typ = OpType::Keyword(KeywordType::ConstantDef);
+        const_def.text = name.text.clone();
+
+        let mut code = Vec::new();
+        loop {
+            let Some(t) = rtokens.pop() else {
+                lerror!(&op.loc, "Constant was not closed with an 'end' instruction, expected 'end' but found nothing");
+                bail!("");
+            };
+            if t.typ == OpType::Keyword(KeywordType::End) {
+                break;
+            }
+            code.push(t);
+        }
+
+        // a single string is a string constant, anything else gets evaluated
+        let value = match &code[..] {
+            [s] if s.tok_typ == TokenType::String => ConstValue::Str(s.text.clone()),
+            [s] if s.tok_typ == TokenType::CString => ConstValue::CStr(s.text.clone()),
+            _ => ConstValue::Int(self.eval(op, code, false)?)
+        };
+        match &value {
+            ConstValue::Int(v) => const_def.value = *v,
+            ConstValue::Str(_) => const_def.tok_typ = TokenType::String,
+            ConstValue::CStr(_) => const_def.tok_typ = TokenType::CString,
+        }
+
+        if private {
+            self.privates.push(name.text.clone());
+        }
         self.program.constants.insert(name.text.clone(), Constant{
             loc: name.loc.clone(),
-            name: name.text.clone(),
+            name: name.text,
+            value,
+            doc
         });
 
-        // println!("{:?}", self.program.constants);
+        program.push(const_def);
+        Ok(())
+    }
 
-        let mut const_def = op.clone();
-        const_def.typ = OpType::Keyword(KeywordType::ConstantDef);
-        const_def.text = name.text;
+    /// `enum Name do A B = 4 C end`, every variant is a constant `Name.A` that counts up from the one before it
+    fn handle_enum(&mut self, rtokens: &mut Vec<Operator>, op: &mut Operator, program: &mut Vec<Operator>) -> Result<()> {
+        let Some(name) = rtokens.pop() else {
+            lerror!(&op.loc, "Enum name not found, expected {} but found nothing", TokenType::Word.human());
+            bail!("");
+        };
+        let doc = self.doc.take();
+        let private = std::mem::take(&mut self.f_priv);
 
-        let item = rtokens.pop().unwrap();
-        if item.tok_typ == TokenType::Int {
-            const_def.value = item.value;
-        } else {
-            lerror!(&op.loc, "For now only {:?} is allowed in constants", TokenType::Int);
+        let valid = |n: &Operator| !n.text.starts_with(|c: char| c.is_ascii_digit()) &&
+            n.text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid(&name) {
+            lerror!(&name.loc, "Enum name '{}' can only contain letters, numbers, '_' and '-', and cannot start with a number", name.text);
             bail!("");
         }
+        self.is_word_available(&name, KeywordType::Enum)?;
 
-        let posibly_end = rtokens.pop();
-        // println!("end: {posibly_end:?}");
-        if posibly_end.is_none() || posibly_end.unwrap().typ != OpType::Keyword(KeywordType::End) {
-            lerror!(&op.loc, "Constant was not closed with an 'end' instruction, expected 'end' but found nothing");
-            bail!("");
+        match rtokens.pop() {
+            Some(t) if t.typ == OpType::Keyword(KeywordType::Do) => (),
+            Some(t) => {
+                lerror!(&t.loc, "Expected 'do' after the enum name, got {}", t.typ.human());
+                bail!("");
+            }
+            None => {
+                lerror!(&name.loc, "Expected 'do' after the enum name, got nothing");
+                bail!("");
+            }
         }
-        // token.value = 
 
-        program.push(const_def);
+        let mut next = 0;
+        loop {
+            let Some(mut variant) = rtokens.pop() else {
+                lerror!(&op.loc, "Enum was not closed with an 'end' instruction, expected 'end' but found nothing");
+                bail!("");
+            };
+            if variant.typ == OpType::Keyword(KeywordType::End) {
+                break;
+            }
+            if variant.tok_typ != TokenType::Word || !valid(&variant) {
+                lerror!(&variant.loc, "Expected an enum variant name, got '{}'", variant.text);
+                bail!("");
+            }
+
+            // an explicit value, the variants after it count up from there
+            if rtokens.last().is_some_and(|t| t.typ == OpType::Instruction(InstructionType::Equals)) {
+                let eq = rtokens.pop().unwrap();
+                let Some(value) = rtokens.pop() else {
+                    lerror!(&eq.loc, "Expected a value after '=', got nothing");
+                    bail!("");
+                };
+                next = self.eval(&value, vec![value.clone()], false)?;
+            }
+
+            variant.text = format!("{}.{}", name.text, variant.text);
+            self.is_word_available(&variant, KeywordType::Constant)?;
+
+            let mut const_def = variant.clone();
+            const_def.typ = OpType::Keyword(KeywordType::ConstantDef);
+            const_def.value = next;
+
+            if private {
+                self.privates.push(variant.text.clone());
+            }
+            self.program.constants.insert(variant.text.clone(), Constant{
+                loc: variant.loc.clone(),
+                name: variant.text,
+                value: ConstValue::Int(next),
+                doc: None
+            });
+            program.push(const_def);
+            next += 1;
+        }
+
+        if private {
+            self.privates.push(name.text.clone());
+        }
+        self.program.enums.insert(name.text.clone(), Enum{
+            loc: name.loc,
+            name: name.text,
+            doc
+        });
         Ok(())
     }
 
-    fn handle_struct(&mut self, rtokens: &mut Vec<Operator>, op: &mut Operator, program: &mut Vec<Operator>) -> Result<()> {
+    /// `struct Name do field -> type ... end`, fields are laid out in order with natural alignment
+    fn handle_struct(&mut self, rtokens: &mut Vec<Operator>, op: &mut Operator) -> Result<()> {
         let Some(name) = rtokens.pop() else {
             lerror!(&op.loc, "Struct name not found, expected {} but found nothing", TokenType::Word.human());
             bail!("");
         };
+        let private = std::mem::take(&mut self.f_priv);
 
         if let '0'..='9' | '.' = name.text.chars().next().unwrap() {
             lerror!(&name.loc, "Struct name starts with a number or dot which is not allowed");
             bail!("");
         }
+        if name.text.contains('.') {
+            lerror!(&name.loc, "Struct name contains '.', which is unsupported");
+            bail!("");
+        }
 
         self.is_word_available(&name, KeywordType::Struct)?;
 
@@ -487,10 +1078,15 @@ impl<'a> Preprocessor<'a> {
             loc: name.loc,
             name: name.text,
             fields: vec![],
+            size: 0,
+            align: 1
         };
 
         loop {
-            let fl_name = rtokens.pop().unwrap();
+            let Some(fl_name) = rtokens.pop() else {
+                lerror!(&structure.loc, "Struct was not closed with an 'end' instruction, expected 'end' but found nothing");
+                bail!("");
+            };
 
             if fl_name.typ == OpType::Keyword(KeywordType::End) {
                 break;
@@ -515,6 +1111,11 @@ impl<'a> Preprocessor<'a> {
                 }
             }
 
+            if let Some(f) = structure.fields.iter().find(|f| f.name == fl_name.text) {
+                lerror!(&fl_name.loc, "Struct '{}' already has a field '{}'", structure.name, f.name);
+                bail!("");
+            }
+
             if let Some(arrow) = rtokens.pop() {
                 if arrow.typ != OpType::Internal(InternalType::Arrow) {
                     lerror!(&arrow.loc, "Expected '->' but found {:?}", arrow.typ);
@@ -531,48 +1132,508 @@ impl<'a> Preprocessor<'a> {
                 bail!("");
             };
 
-            let Ok(typ) = Types::from_string(&typ.text) else {
-                lerror!(&typ.loc, "Expected a type but found {:?}", typ.text);
+            // other structs are embedded, everything else is aligned to its size
+            let (typ, size, align) = if let Some(s) = self.program.struct_defs.get(&typ.text) {
+                (Types::Struct(s.name.clone()), s.size, s.align)
+            } else if let Some(e) = self.program.enums.get(&typ.text) {
+                (Types::Enum(e.name.clone()), 8, 8)
+            } else {
+                match Types::from_string(&typ.text) {
+                    Ok(t) if t.get_size() > 0 => {
+                        let size = t.get_size() as usize;
+                        (t, size, size)
+                    }
+                    _ => {
+                        lerror!(&typ.loc, "Expected a type but found {:?}", typ.text);
+                        bail!("");
+                    }
+                }
+            };
+
+            let offset = structure.size.next_multiple_of(align);
+            structure.fields.push(StructField { name: fl_name.text, typ, offset });
+            structure.size = offset + size;
+            structure.align = structure.align.max(align);
+        }
+        structure.size = structure.size.next_multiple_of(structure.align);
+
+        if private {
+            self.privates.push(structure.name.clone());
+        }
+        self.program.struct_defs.insert(structure.name.clone(), structure);
+        Ok(())
+    }
+
+    /// The fields a path like `inner.x` goes through, starting in the struct `def`
+    fn field_path(&self, def: &StructDef, path: &[&str], loc: &Loc) -> Result<Vec<(String, StructField)>> {
+        let mut fields = Vec::new();
+        let mut def = def;
+        for (i, part) in path.iter().enumerate() {
+            let Some(f) = def.fields.iter().find(|f| f.name == *part) else {
+                lerror!(loc, "Struct '{}' has no field '{}'", def.name, part);
+                linfo!(&def.loc, "'{}' is defined here", def.name);
                 bail!("");
             };
-            
-            structure.fields.push((fl_name.text, typ));
-            
+            fields.push((def.name.clone(), f.clone()));
+
+            if i + 1 == path.len() {
+                break;
+            }
+            // structs are looked up by their key, fields only know the internal name
+            let Some(inner) = (match &f.typ {
+                Types::Struct(inner) => self.program.struct_defs.values().find(|s| s.name == *inner),
+                _ => None
+            }) else {
+                lerror!(loc, "Field '{}' of '{}' is not a struct, it has no field '{}'", f.name, def.name, path[i + 1]);
+                bail!("");
+            };
+            def = inner;
         }
+        Ok(fields)
+    }
 
-        self.program.struct_defs.insert(structure.name.clone(), structure.clone());
+    /// `sizeof(Struct)` and `offsetof(Struct, field)`, there can be a space after the ','
+    /// which makes the rest of `offsetof` the next word
+    fn struct_constant(&self, t: &Operator, rest: &mut dyn Iterator<Item = Operator>) -> Result<Option<usize>> {
+        if let Some(name) = t.text.strip_prefix("sizeof(").and_then(|n| n.strip_suffix(')')) {
+            return Ok(self.program.struct_defs.get(name).map(|s| s.size));
+        }
+        let Some(args) = t.text.strip_prefix("offsetof(") else {
+            return Ok(None);
+        };
 
-        if let Some(def_name) = rtokens.pop() {
-            if def_name.typ == OpType::Instruction(InstructionType::None){
-                let mut def = def_name.clone();
-                
-                def.typ = OpType::Internal(InternalType::StructAlloc {
-                    name: structure.name.clone()
-                });
-                self.program.struct_allocs.insert(def_name.text, structure.name);
-                program.push(def);
-                
-            } else {
-                rtokens.push(def_name);
+        let mut args = args.to_string();
+        while !args.ends_with(')') {
+            let Some(next) = rest.next() else {
+                lerror!(&t.loc, "Expected ')' to close 'offsetof', got nothing");
+                bail!("");
+            };
+            args.push_str(&next.text);
+        }
+        let Some((name, path)) = args[..args.len() - 1].split_once(',') else {
+            lerror!(&t.loc, "Expected 'offsetof(Struct, field)', got 'offsetof({args}'");
+            bail!("");
+        };
+        let Some(def) = self.program.struct_defs.get(name.trim()) else {
+            lerror!(&t.loc, "Unknown struct '{}' in 'offsetof'", name.trim());
+            bail!("");
+        };
+        let path = path.trim().split('.').collect::<Vec<&str>>();
+        Ok(Some(self.field_path(def, &path, &t.loc)?.iter().map(|(_, f)| f.offset).sum()))
+    }
+
+    /// `fn(<args> -> <returns>)` or a cast to it, with a signature `Types::from_signature` can read
+    fn fn_type(&self, t: &Operator, rest: &mut dyn Iterator<Item = Operator>) -> Result<Option<(InstructionType, String)>> {
+        let (typ, sig, close) = if let Some(sig) = t.text.strip_prefix("fn(") {
+            (InstructionType::TypeFn, sig, ")")
+        } else if let Some(sig) = t.text.strip_prefix("cast(fn(") {
+            (InstructionType::CastFn, sig, "))")
+        } else {
+            return Ok(None);
+        };
+
+        let mut sig = sig.to_string();
+        while !sig.ends_with(close) {
+            let Some(next) = rest.next() else {
+                lerror!(&t.loc, "Expected ')' to close the function pointer type, got nothing");
+                bail!("");
+            };
+            sig.push(' ');
+            sig.push_str(&next.text);
+        }
+        sig.truncate(sig.len() - close.len());
+
+        let sig = sig.replace("->", " -> ");
+        let (args, returns) = sig.split_once("->").unwrap_or((&sig, ""));
+        if returns.contains("->") {
+            lerror!(&t.loc, "A function pointer type can only have one '->'");
+            bail!("");
+        }
+        let read = |s: &str| -> Result<Vec<String>> {
+            let mut types = Vec::new();
+            for w in s.split_whitespace() {
+                let typ = match w {
+                    "int" => Types::U64,
+                    w @ ("u8" | "u16" | "u32" | "u64" | "i8" | "i16" | "i32" | "i64") => Types::from_string(&w)?,
+                    "bool" => Types::Bool,
+                    "ptr" => Types::Ptr,
+                    "any" => Types::Any,
+                    // void is the same as no types
+                    "void" => continue,
+                    w if w.len() > 1 && w.starts_with('\'') => Types::Var(w[1..].to_string()),
+                    w => if let Some(e) = self.program.enums.get(w) {
+                        Types::Enum(e.name.clone())
+                    } else if let Some(t) = self.array_type(w) {
+                        t
+                    } else if let Some(s) = self.program.struct_defs.get(w) {
+                        Types::Struct(s.name.clone())
+                    } else {
+                        lerror!(&t.loc, "Unknown type '{}' in a function pointer type", w);
+                        bail!("");
+                    }
+                };
+                types.push(typ.signature_name());
+            }
+            Ok(types)
+        };
+        Ok(Some((typ, format!("{} -> {}", read(args)?.join(" "), read(returns)?.join(" ")).trim().to_string())))
+    }
+
+    fn handle_asm(&mut self, rtokens: &mut Vec<Operator>, op: &mut Operator, program: &mut Vec<Operator>) -> Result<()> {
+        let mut signature: Vec<Operator> = Vec::new();
+        loop {
+            let Some(t) = rtokens.pop() else {
+                lerror!(&op.loc, "Expected 'then' after the asm signature but found nothing");
+                bail!("");
+            };
+
+            match t.typ {
+                OpType::Instruction(
+                    InstructionType::With |
+                    InstructionType::Returns |
+                    InstructionType::TypeAny |
+                    InstructionType::TypeBool |
+                    InstructionType::TypeInt |
+                    InstructionType::TypeSized |
+                    InstructionType::TypePtr |
+                    InstructionType::TypeVoid
+                ) => signature.push(t),
+                OpType::Keyword(KeywordType::FunctionThen) => {
+                    signature.push(t);
+                    break;
+                }
+                _ => {
+                    lerror!(&t.loc, "Expected a type or 'then' in the asm signature but found {:?}", t.text);
+                    bail!("");
+                }
             }
         }
 
+        if signature.first().map(|t| t.typ.clone()) != Some(OpType::Instruction(InstructionType::With)) {
+            lerror!(&op.loc, "Expected 'with' after 'asm'");
+            bail!("");
+        }
+
+        // empty bodies never make it past the parser
+        let mut asm = if rtokens.last().is_some_and(|t| t.tok_typ == TokenType::Asm) {
+            rtokens.pop().unwrap()
+        } else {
+            let mut t = op.clone();
+            t.text = String::new();
+            t.tok_typ = TokenType::Asm;
+            t
+        };
+        asm.typ = OpType::Instruction(InstructionType::InlineAsm);
+        asm.loc = op.loc.clone();
+
+        let mut fallback: Vec<Operator> = Vec::new();
+        let mut end = loop {
+            let Some(t) = rtokens.pop() else {
+                lerror!(&op.loc, "Asm block was not closed with 'done'");
+                bail!("");
+            };
 
+            match t.typ {
+                OpType::Keyword(KeywordType::FunctionDone) => break t,
+                OpType::Keyword(KeywordType::Else) if asm.value == 0 => asm.value = 1,
+                _ if asm.value == 1 => fallback.push(t),
+                _ => {
+                    lerror!(&t.loc, "Expected 'else' or 'done' after the asm body but found {:?}", t.text);
+                    bail!("");
+                }
+            }
+        };
+        end.typ = OpType::Keyword(KeywordType::End);
+
+        //? value is 1 if there is an interpreter fallback
+        program.push(asm);
+        program.append(&mut signature);
+        program.append(&mut fallback);
+        program.push(end);
         Ok(())
     }
 
+    fn handle_macro(&mut self, rtokens: &mut Vec<Operator>, op: &Operator) -> Result<()> {
+        let Some(name) = rtokens.pop() else {
+            lerror!(&op.loc, "Macro name not found, expected {} but found nothing", TokenType::Word.human());
+            bail!("");
+        };
+
+        self.is_word_available(&name, KeywordType::Macro)?;
+        if std::mem::take(&mut self.f_priv) {
+            self.privates.push(name.text.clone());
+        }
+
+        let Some(tokens) = definition_body(rtokens) else {
+            lerror!(&op.loc, "Macro '{}' was not closed with an 'end'", name.text);
+            bail!("");
+        };
+
+        self.program.macros.insert(name.text.clone(), Macro {
+            loc: name.loc.clone(),
+            name: name.text,
+            tokens
+        });
+        Ok(())
+    }
+
+    /// `case <value> do` and `default do`, the value is a constant expression that gets stored in the 'case'
+    fn handle_case(&mut self, rtokens: &mut Vec<Operator>, op: &mut Operator, program: &mut Vec<Operator>) -> Result<()> {
+        let mut code = Vec::new();
+        loop {
+            let Some(t) = rtokens.pop() else {
+                lerror!(&op.loc, "Expected 'do' after '{}', but found nothing", op.typ.human());
+                bail!("");
+            };
+            match t.typ {
+                OpType::Keyword(KeywordType::Do) => break,
+                OpType::Keyword(KeywordType::End | KeywordType::Case | KeywordType::Default) => {
+                    lerror!(&t.loc, "Expected 'do' after '{}', but found '{}'", op.typ.human(), t.typ.human());
+                    bail!("");
+                }
+                _ => code.push(t)
+            }
+        }
+
+        if op.typ == OpType::Keyword(KeywordType::Default) {
+            if let Some(t) = code.first() {
+                lerror!(&t.loc, "'default' does not take a value, expected 'do'");
+                bail!("");
+            }
+        } else if code.is_empty() {
+            lerror!(&op.loc, "Expected a value after 'case', but found 'do'");
+            bail!("");
+        } else {
+            op.value = self.eval(op, code, false)?;
+        }
+        program.push(op.clone());
+        Ok(())
+    }
+
+    /// `#if <condition> #then ... #else ... #end`, the branch that is taken goes back in front
+    /// so it gets preprocessed as if the directives were never there
+    fn handle_cond(&mut self, rtokens: &mut Vec<Operator>, op: &Operator) -> Result<()> {
+        let mut cond = Vec::new();
+        loop {
+            let Some(t) = rtokens.pop() else {
+                lerror!(&op.loc, "Expected '#then' after the '#if' condition, got nothing");
+                bail!("");
+            };
+            if t.typ == OpType::Keyword(KeywordType::CondThen) {
+                break;
+            }
+            cond.push(t);
+        }
+        let taken = self.eval(op, cond, true)? != 0;
+
+        let mut then_body = Vec::new();
+        let mut else_body = Vec::new();
+        let mut in_else = false;
+        let mut depth = 0;
+        loop {
+            let Some(t) = rtokens.pop() else {
+                lerror!(&op.loc, "'#if' was not closed with an '#end'");
+                bail!("");
+            };
+            match t.typ {
+                OpType::Keyword(KeywordType::CondEnd) if depth == 0 => break,
+                OpType::Keyword(KeywordType::CondElse) if depth == 0 => {
+                    if in_else {
+                        lerror!(&t.loc, "'#if' can only have one '#else'");
+                        bail!("");
+                    }
+                    in_else = true;
+                    continue;
+                }
+                OpType::Keyword(KeywordType::CondIf) => depth += 1,
+                OpType::Keyword(KeywordType::CondEnd) => depth -= 1,
+                _ => ()
+            }
+            if in_else {
+                else_body.push(t);
+            } else {
+                then_body.push(t);
+            }
+        }
+
+        let mut body = if taken { then_body } else { else_body };
+        body.reverse();
+        rtokens.append(&mut body);
+        Ok(())
+    }
+
+    /// Evaluates `code` with `precompile`, words in it can be integer constants, and in '#if'
+    /// conditions also '-D' defines, the target constants and `defined NAME`
+    fn eval(&self, op: &Operator, code: Vec<Operator>, is_cond: bool) -> Result<usize> {
+        let what = if is_cond { "'#if' condition" } else { "constant expression" };
+        let mut resolved = Vec::new();
+        let mut code = self.expand_macros(code)?.into_iter();
+        while let Some(mut t) = code.next() {
+            if let Some(size) = deprecated_size(&t) {
+                resolved.push(internal_op(&t, InstructionType::PushInt, "", size));
+                continue;
+            }
+            if t.typ != OpType::Instruction(InstructionType::None) {
+                resolved.push(t);
+                continue;
+            }
+
+            let value = if is_cond && t.text == "defined" {
+                let Some(name) = code.next() else {
+                    lerror!(&t.loc, "Expected a name after 'defined', got nothing");
+                    bail!("");
+                };
+                usize::from(self.is_defined(&name.text)?)
+            } else if let Some(v) = if is_cond { self.define(&t.text)? } else { None } {
+                v
+            } else if let Some(v) = self.struct_constant(&t, &mut code)? {
+                v
+            } else if let Some(c) = self.program.constants.get(&t.text) {
+                let ConstValue::Int(v) = c.value else {
+                    lerror!(&t.loc, "String constant '{}' cannot be used in a {what}", t.text);
+                    linfo!(&c.loc, "defined here");
+                    bail!("");
+                };
+                v
+            } else {
+                lerror!(&t.loc, "Unknown word '{}' in {what}", t.text);
+                if is_cond {
+                    linfo!(&t.loc, "use 'defined {}' to check if it exists", t.text);
+                }
+                t.note_expansion();
+                bail!("");
+            };
+            t.typ = OpType::Instruction(InstructionType::PushInt);
+            t.value = value;
+            resolved.push(t);
+        }
+
+        let res = precompile(&resolved)?;
+        if res.len() != 1 {
+            lerror!(&op.loc, "Expected the {what} to leave 1 value, got {:?}", res);
+            bail!("");
+        }
+        Ok(res[0])
+    }
+
+    /// Value of a '-D' define or one of the target constants
+    fn define(&self, name: &str) -> Result<Option<usize>> {
+        for d in &self.args.defines {
+            let (n, v) = d.split_once('=').unwrap_or((d, "1"));
+            if n == name {
+                return match crate::lexer::parse_int(v) {
+                    Ok(v) => Ok(Some(v)),
+                    Err(e) => {
+                        crate::error!("Bad value for define '{n}': {e}");
+                        bail!("");
+                    }
+                };
+            }
+        }
+        Ok(match name {
+            "ARCH_X86_64" | "OS_LINUX" => Some(1),
+            "DEBUG" => Some(usize::from(self.args.get_opt_level()? == 0)),
+            "OPT_LEVEL" => Some(self.args.get_opt_level()?),
+            _ => None
+        })
+    }
+
+    fn is_defined(&self, name: &str) -> Result<bool> {
+        Ok(self.define(name)?.is_some() ||
+            self.program.constants.contains_key(name) ||
+            self.program.functions.contains_key(name) ||
+            self.program.memories.contains_key(name) ||
+            self.program.macros.contains_key(name) ||
+            self.program.enums.contains_key(name) ||
+            self.program.struct_defs.contains_key(name))
+    }
+
+    /// Body of the macro `op` uses, every op in it remembers that it came from here
+    fn expand_macro(&self, op: &Operator) -> Result<Vec<Operator>> {
+        let m = &self.program.macros[&op.text];
+        if op.expansion.len() >= self.args.macro_depth {
+            lerror!(&op.loc, "Macro '{}' expands more than {} levels deep, is it recursive?", op.text, self.args.macro_depth);
+            linfo!(&m.loc, "macro defined here");
+            op.note_expansion();
+            bail!("");
+        }
+
+        let mut expansion = vec![(m.name.clone(), op.loc.clone())];
+        expansion.extend(op.expansion.iter().cloned());
+        Ok(m.tokens.iter().map(|t| {
+            let mut t = t.clone();
+            t.expansion.clone_from(&expansion);
+            t
+        }).collect())
+    }
+
+    /// Expands every macro in `ops`, for code that gets evaluated before it goes through `preprocess`
+    fn expand_macros(&self, ops: Vec<Operator>) -> Result<Vec<Operator>> {
+        let mut out = Vec::new();
+        for op in ops {
+            if op.typ == OpType::Instruction(InstructionType::None) && self.program.macros.contains_key(&op.text) {
+                let body = self.expand_macro(&op)?;
+                out.append(&mut self.expand_macros(body)?);
+            } else {
+                out.push(op);
+            }
+        }
+        Ok(out)
+    }
+
     pub fn expand(&mut self) -> Result<()> {
         let mut program: Vec<Operator> = Vec::new();
         // println!("{:?}", self.program.functions);
         let mut rtokens = self.program.ops.clone();
         rtokens.reverse();
+        let mut current_fn: Option<String> = None;
+        // types in the signature of a function or an asm block are not values
+        let mut in_signature = false;
 
-        'main_loop: while !rtokens.is_empty() {
-            let op = rtokens.pop().unwrap();
+        while let Some(op) = rtokens.pop() {
             let op_type = op.typ.clone();
+            if op_type == OpType::Instruction(InstructionType::InlineAsm) {
+                in_signature = true;
+            }
             if op.tok_typ == TokenType::Word {
                 match op_type {
+                    OpType::Keyword(KeywordType::FunctionDef | KeywordType::FunctionDefExported) => {
+                        let mut t = op.clone();
+                        if let Some(f) = self.program.functions.get(&op.text) {
+                            t.value = f.frame_size;
+                        }
+                        current_fn = Some(op.text.clone());
+                        in_signature = true;
+                        program.push(t);
+                    }
+                    OpType::Keyword(KeywordType::FunctionThen) => {
+                        in_signature = false;
+                        program.push(op.clone());
+                    }
+                    OpType::Instruction(InstructionType::TypeSized) if !in_signature => {
+                        match deprecated_size(&op) {
+                            Some(size) => program.push(internal_op(&op, InstructionType::PushInt, "", size)),
+                            None => program.push(op.clone())
+                        }
+                    }
+                    OpType::Keyword(KeywordType::FunctionDone) => {
+                        current_fn = None;
+                        program.push(op.clone());
+                    }
                     OpType::Instruction(InstructionType::None) => {
+                        let local = current_fn.as_ref()
+                            .and_then(|f| self.program.functions.get(f))
+                            .and_then(|f| f.memories.get(&op.text));
+
+                        if let Some(local) = local {
+                            let mut t = op.clone();
+                            t.addr = Some(local.id);
+                            t.typ = OpType::Instruction(InstructionType::LocalMemUse);
+                            program.push(t);
+                            program.extend(memory_type(&op, local));
+                            continue;
+                        }
+
                         let m = self.program.functions.get(&op.text.clone().replace('(', "__OP_PAREN__").replace(')', "__CL_PAREN__"));
                         let mem = self.program.memories.get(&op.text);
                         let cons = self.program.constants.get(&op.text.clone().replace('(', "__OP_PAREN__").replace(')', "__CL_PAREN__"));
@@ -595,29 +1656,86 @@ impl<'a> Preprocessor<'a> {
                             t.addr = Some(mem.id);
                             t.typ = OpType::Instruction(InstructionType::MemUse);
                             program.push(t);
+                            program.extend(memory_type(&op, mem));
                         } else if let Some(cons) = cons {
                             let mut t = op.clone();
                             t.text = cons.name.clone();
                             t.typ = OpType::Instruction(InstructionType::ConstUse);
                             program.push(t);
-                        } else {
+                        } else if let Some(e) = self.program.enums.get(&op.text) {
                             let mut t = op.clone();
-                            let parts = op.text.split('.').map(|f| f.to_string()).collect::<Vec<String>>();
-                            let alc = self.program.struct_allocs.get(&parts[0]);
-                            if let Some(alc) = alc {
-                                if let Some(def) = self.program.struct_defs.get(alc) {
-                                    // if def.fields.iter().for_each(|f| f.0 == parts[1])
-                                    println!("{:?}", def.fields);
-                                    if def.fields.iter().find(|f| f.0 == parts[1]).is_some() || parts.len() < 2{
-                                        t.typ = OpType::Instruction(InstructionType::StructUse);
-                                        program.push(t);
-                                        continue 'main_loop;
-                                    }
+                            t.text = e.name.clone();
+                            t.typ = OpType::Instruction(InstructionType::TypeEnum);
+                            program.push(t);
+                        } else if let Some(e) = op.text.strip_prefix("cast(").and_then(|t| t.strip_suffix(')')).and_then(|t| self.program.enums.get(t)) {
+                            let mut t = op.clone();
+                            t.text = e.name.clone();
+                            t.typ = OpType::Instruction(InstructionType::CastEnum);
+                            program.push(t);
+                        } else if let Some(name) = op.text.strip_prefix('&') {
+                            let f = self.program.functions.get(&name.replace('(', "__OP_PAREN__").replace(')', "__CL_PAREN__"));
+                            let Some(f) = f else {
+                                lerror!(&op.loc, "Can only take the address of a function, '{}' is not one", name);
+                                op.note_expansion();
+                                bail!("");
+                            };
+                            if f.inline {
+                                lerror!(&op.loc, "Cannot take the address of the inline function '{}'", name);
+                                linfo!(&f.loc, "defined here");
+                                op.note_expansion();
+                                bail!("");
+                            }
+                            program.push(internal_op(&op, InstructionType::FnAddr, &f.name, 0));
+                        } else if let Some(var) = op.text.strip_prefix('\'') {
+                            program.push(internal_op(&op, InstructionType::TypeVar, var, 0));
+                        } else if let Some((typ, sig)) = self.fn_type(&op, &mut std::iter::from_fn(|| rtokens.pop()))? {
+                            program.push(internal_op(&op, typ, &sig, 0));
+                        } else if let Some(v) = self.struct_constant(&op, &mut std::iter::from_fn(|| rtokens.pop()))? {
+                            program.push(internal_op(&op, InstructionType::PushInt, "", v));
+                        } else if let Some(t) = self.array_type(&op.text) {
+                            program.push(internal_op(&op, InstructionType::TypeArray, &t.element_name(), 0));
+                        } else if let Some(t) = op.text.strip_prefix("cast(").and_then(|t| t.strip_suffix(')')).and_then(|t| self.array_type(t)) {
+                            program.push(internal_op(&op, InstructionType::CastArray, &t.element_name(), 0));
+                        } else if let Some(def) = self.program.struct_defs.get(&op.text) {
+                            program.push(internal_op(&op, InstructionType::TypeStruct, &def.name, 0));
+                        } else if let Some(def) = op.text.strip_prefix("cast(").and_then(|t| t.strip_suffix(')')).and_then(|t| self.program.struct_defs.get(t)) {
+                            program.push(internal_op(&op, InstructionType::CastStruct, &def.name, 0));
+                        } else if let Some((head, path)) = op.text.split_once('.') {
+                            // `instance.field` pushes the address of the field, `Struct.field` takes the instance from the stack
+                            let local = current_fn.as_ref()
+                                .and_then(|f| self.program.functions.get(f))
+                                .and_then(|f| f.memories.get(head));
+                            let instance = local.or_else(|| self.program.memories.get(head))
+                                .and_then(|m| m.instance.as_ref().map(|s| (m, s)));
+
+                            let def = if let Some((m, s)) = instance {
+                                let typ = if local.is_some() { InstructionType::LocalMemUse } else { InstructionType::MemUse };
+                                let mut t = internal_op(&op, typ, head, 0);
+                                t.addr = Some(m.id);
+                                program.push(t);
+                                program.push(internal_op(&op, InstructionType::CastStruct, s, 0));
+                                self.program.struct_defs.values().find(|d| d.name == *s)
+                            } else {
+                                self.program.struct_defs.get(head)
+                            };
+                            let Some(def) = def else {
+                                lerror!(&op.loc, "Preprocess: Unknown word '{}'", op.text.clone());
+                                op.note_expansion();
+                                bail!("");
+                            };
+
+                            let path = path.split('.').collect::<Vec<&str>>();
+                            for (owner, f) in self.field_path(def, &path, &op.loc)? {
+                                program.push(internal_op(&op, InstructionType::Field, &owner, f.offset));
+                                // only the last field can be something else than a struct, `@` and `!` work on it
+                                match &f.typ {
+                                    Types::Struct(inner) => program.push(internal_op(&op, InstructionType::CastStruct, inner, 0)),
+                                    t => program.push(internal_op(&op, InstructionType::CastArray, &format!("{}[1]", t.element_name()), 0))
                                 }
                             }
-
-
+                        } else {
                             lerror!(&op.loc, "Preprocess: Unknown word '{}'", op.text.clone());
+                            op.note_expansion();
                             bail!("");
                         }
                     }
@@ -645,15 +1763,14 @@ impl<'a> Preprocessor<'a> {
 
     
 
-    pub fn get_ops(&mut self) -> Vec<Operator> {
-        self.program.ops.clone()
-    }
     pub fn is_word_available(&self, word: &Operator, typ: KeywordType) -> Result<bool> {
 
         match typ {
             KeywordType::Memory |
             KeywordType::Constant |
             KeywordType::Struct |
+            KeywordType::Macro |
+            KeywordType::Enum |
             KeywordType::Function => (),
             _ => panic!()
         }
@@ -711,9 +1828,37 @@ impl<'a> Preprocessor<'a> {
             bail!("");
         }
 
+        let mc = self.program.macros.get(&word.text);
+        if let Some(mc) = mc {
+            if typ == KeywordType::Macro {
+                lerror!(&word.loc, "Macros cannot be redefined, got {}", word.text);
+                linfo!(&mc.loc, "first definition here"); 
+                if crate::DEV_MODE {println!("{word:?}")}
+                bail!("");
+            }
+            lerror!(&word.loc, "{typ:?} cannot replace macro, got {}", word.text);
+            linfo!(&mc.loc, "first definition here"); 
+            if crate::DEV_MODE {println!("{word:?}")}
+            bail!("");
+        }
+
+        let e = self.program.enums.get(&word.text);
+        if let Some(e) = e {
+            if typ == KeywordType::Enum {
+                lerror!(&word.loc, "Enums cannot be redefined, got {}", word.text);
+                linfo!(&e.loc, "first definition here"); 
+                if crate::DEV_MODE {println!("{word:?}")}
+                bail!("");
+            }
+            lerror!(&word.loc, "{typ:?} cannot replace enum, got {}", word.text);
+            linfo!(&e.loc, "first definition here"); 
+            if crate::DEV_MODE {println!("{word:?}")}
+            bail!("");
+        }
+
         let s = self.program.struct_defs.get(&word.text);
         if let Some(s) = s {
-            if typ == KeywordType::Constant {
+            if typ == KeywordType::Struct {
                 lerror!(&word.loc, "Structs cannot be redefined, got {}", word.text);
                 linfo!(&s.loc, "first definition here"); 
                 if crate::DEV_MODE {println!("{word:?}")}
@@ -728,27 +1873,72 @@ impl<'a> Preprocessor<'a> {
         Ok(true)
     }
 
-    pub fn set_functions(&mut self, f: Functions) {
-        self.program.functions = f;
-    }
-    pub fn set_constants(&mut self, f: Constants) {
-        self.program.constants = f;
-    }
-    pub fn set_memories(&mut self, f: Memories) {
-        self.program.memories = f;
-    }
 
-    pub fn get_functions(&mut self) -> Functions {
-        self.program.functions.clone()
+    pub fn get_program(&mut self) -> Program {
+        std::mem::take(&mut self.program)
     }
-    pub fn get_constants(&mut self) -> Constants {
-        self.program.constants.clone()
+}
+
+/// Takes the tokens up to the 'end' of a definition, `None` if there is no 'end'. The body can
+/// have control flow and definitions in it, so the blocks need to be counted to find the right one
+fn definition_body(rtokens: &mut Vec<Operator>) -> Option<Vec<Operator>> {
+    let mut tokens = Vec::new();
+    let mut depth = 0;
+    // 'elif' and 'while' waiting for their 'do', and the depth they are at. The 'do' of an 'elif'
+    // continues the 'if' instead of opening a block
+    let mut pending: Vec<(usize, KeywordType)> = Vec::new();
+    loop {
+        let t = rtokens.pop()?;
+        match &t.typ {
+            OpType::Keyword(KeywordType::End) if depth == 0 => return Some(tokens),
+            OpType::Keyword(KeywordType::End) => depth -= 1,
+            OpType::Keyword(k @ (KeywordType::Elif | KeywordType::While)) => pending.push((depth, *k)),
+            OpType::Keyword(KeywordType::Do) => {
+                let opener = pending.pop_if(|(d, _)| *d == depth).map(|(_, k)| k);
+                if opener != Some(KeywordType::Elif) {
+                    depth += 1;
+                }
+            }
+            OpType::Keyword(KeywordType::If | KeywordType::Match | KeywordType::Memory | KeywordType::Constant |
+                KeywordType::Macro | KeywordType::Alloc) => depth += 1,
+            _ => ()
+        }
+        tokens.push(t);
     }
-    pub fn get_memories(&mut self) -> Memories{
-        self.program.memories.clone()
+}
+
+/// Ops that give the pointer to the memory `m` its type, if it is an array or a struct instance
+fn memory_type(op: &Operator, m: &Memory) -> Option<Operator> {
+    if let Some((elem, count)) = &m.array {
+        Some(internal_op(op, InstructionType::CastArray, &format!("{elem}[{count}]"), 0))
+    } else {
+        m.instance.as_ref().map(|s| internal_op(op, InstructionType::CastStruct, s, 0))
     }
+}
 
-    pub fn get_program(&mut self) -> Program {
-        self.program.clone()
+/// The size `u64`, `u32`, `u16` and `u8` push where a value is expected, they were constants
+/// before they were types
+fn deprecated_size(op: &Operator) -> Option<usize> {
+    let size = match op.text.as_str() {
+        "u64" => 8,
+        "u32" => 4,
+        "u16" => 2,
+        "u8" => 1,
+        _ => return None
+    };
+    if op.typ != OpType::Instruction(InstructionType::TypeSized) {
+        return None;
     }
-}
\ No newline at end of file
+    lwarn!(&op.loc, "Using '{0}' as its size is deprecated, use 'sizeof({0})'", op.text);
+    op.note_expansion();
+    Some(size)
+}
+
+/// An op the preprocessor made for the word `op`
+fn internal_op(op: &Operator, typ: InstructionType, text: &str, value: usize) -> Operator {
+    let mut t = op.clone();
+    t.typ = OpType::Instruction(typ);
+    t.text = text.to_string();
+    t.value = value;
+    t
+}
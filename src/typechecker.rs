@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use crate::{definitions::{Operator, Types, OpType, KeywordType, InstructionType, Loc}, Args, lerror, warn};
+use crate::{definitions::{Operator, Types, OpType, KeywordType, InstructionType, Loc, TokenType}, Args, lerror, warn};
 use anyhow::{Result, bail};
 
 #[allow(dead_code)]
@@ -27,38 +27,214 @@ impl Function {
             loc: (String::new(), 0, 0)
         }
     }
+
+    /// The type of a pointer to this function
+    fn pointer(&self) -> Types {
+        let strip = |t: &[Types]| if t.contains(&Types::Void) {Vec::new()} else {t.to_vec()};
+        Types::Fn(strip(&self.args), strip(&self.returns))
+    }
 }
 
 type Functions = HashMap<String, Function>;
 type Constants = HashMap<String, Constant>;
 
-pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>,  funcs: HashMap<String, Function>, consts:  HashMap<String, Constant>) -> Result<(Vec<Types>, Functions, Constants)>{
+/// A block being typechecked, with the stack its paths have to agree on
+enum Block {
+    /// stack before the loop condition
+    While(Vec<Types>),
+    /// stack before the loop condition and after it, where the loop exits
+    Loop(Vec<Types>, Vec<Types>),
+    /// stack after the condition, and what the branches before this one left, an 'if' without
+    /// 'else' has to leave the stack like it was
+    If(Vec<Types>, Option<Vec<Types>>),
+    /// same as `If`, while the condition of an 'elif' is checked
+    Elif(Vec<Types>, Option<Vec<Types>>),
+    /// what the branches before the 'else' left, None if none of them reach the 'end'
+    Else(Option<Vec<Types>>),
+    /// stack after the value is popped, what the arms before this one left, and if it has a
+    /// 'default', without one the arms have to leave the stack like it was
+    Match(Vec<Types>, Option<Vec<Types>>, bool),
+    /// a 'case' or 'default' of the 'match' below it
+    Arm,
+}
+
+/// What the typechecker found out about the ops that are compiled depending on the types they
+/// work on, by their ip
+#[derive(Debug, Default)]
+pub struct Lowering {
+    /// ops that work on signed ints
+    pub signed: Vec<usize>,
+    /// indexes, `@` and `!`, with the element type they work on and the length of the array, 0
+    /// if it is not known
+    pub elements: Vec<(usize, String, usize)>,
+}
+
+/// `first_ip` is where `ops` start in the program, what the ops compile to depends on is added
+/// to `lowering`, `returns` is what the function the ops are in returns
+#[allow(clippy::too_many_arguments)]
+pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>,  funcs: HashMap<String, Function>, consts:  HashMap<String, Constant>, first_ip: usize, returns: Option<&[Types]>, lowering: &mut Lowering) -> Result<(Vec<Types>, Functions, Constants)>{
     if args.unsaf {
         if !args.quiet {
             warn!("Unsafe mode enabled, disabling typechecker, goodluck");
         }
+        // these are compiled for the type they work on, without the typechecker nothing knows it
+        if let Some(op) = ops.iter().find(|op| matches!(op.typ, OpType::Instruction(InstructionType::Index | InstructionType::IndexRead |
+                InstructionType::IndexWrite | InstructionType::FieldRead | InstructionType::FieldWrite))) {
+            lerror!(&op.loc, "'{}' cannot be used in unsafe mode, it needs the typechecker", op.typ.human());
+            bail!("");
+        }
         return Ok((Vec::new(), HashMap::new(), HashMap::new()));
     }
     
     let mut functions: HashMap<String, Function> = funcs;
     let mut constants: HashMap<String, Constant> = consts;
     // let mut in_function: (String, Function, Loc) = (String::new(), Function::default(), (String::new(), 0, 0));
-    let mut stack: Vec<Types> = if let Some(i) = init_types {i} else {Vec::new()};
+    let mut stack: Vec<Types> = init_types.unwrap_or_default();
     let mut stack_snapshots: Vec<Vec<Types>> = Vec::new();
+    let mut blocks: Vec<Block> = Vec::new();
+    // false after a 'break', 'continue' or 'return', until the end of the block its in
+    let mut reachable = true;
+    let end_ip = first_ip + ops.len();
     let mut rtokens = ops;
     rtokens.reverse();
     // println!("{:#?}", ops);
-    while !rtokens.is_empty() {
-        let op = rtokens.pop().unwrap();
+    while let Some(op) = rtokens.pop() {
+        let ip = end_ip - rtokens.len() - 1;
         // println!("{:?}", stack.clone());
         // println!("{:?}", op);
         // println!("{}", ops.len());
         match op.typ.clone() {
             OpType::Keyword(keyword) => {
                 match keyword {
-                    KeywordType::If |
+                    KeywordType::If => {
+                        stack_pop(&mut stack, &op, &[Types::Bool])?;
+                        blocks.push(Block::If(stack.clone(), None));
+                    },
+                    KeywordType::While => {
+                        // the body can change the values, but not their types
+                        stack = forget(&stack);
+                        blocks.push(Block::While(stack.clone()));
+                    },
                     KeywordType::Do => {
                         stack_pop(&mut stack, &op, &[Types::Bool])?;
+                        match blocks.pop() {
+                            Some(Block::While(before)) => blocks.push(Block::Loop(before, stack.clone())),
+                            Some(Block::Elif(before, branches)) => {
+                                if !same_types(&stack, &before) {
+                                    lerror!(&op.loc, "An 'elif' condition can only add a bool to the stack, expected {}, but got {}", show(&before), show(&stack));
+                                    op.note_expansion();
+                                    bail!("");
+                                }
+                                blocks.push(Block::If(before, branches));
+                            }
+                            _ => unreachable!()
+                        }
+                    },
+                    KeywordType::Elif => {
+                        let Some(Block::If(before, mut branches)) = blocks.pop() else { unreachable!() };
+                        add_branch(&mut branches, &stack, reachable, &op, "an 'if'")?;
+                        blocks.push(Block::Elif(before.clone(), branches));
+                        stack = before;
+                        reachable = true;
+                    },
+                    KeywordType::Else => {
+                        let Some(Block::If(before, mut branches)) = blocks.pop() else { unreachable!() };
+                        add_branch(&mut branches, &stack, reachable, &op, "an 'if'")?;
+                        blocks.push(Block::Else(branches));
+                        stack = before;
+                        reachable = true;
+                    },
+                    KeywordType::Match => {
+                        let t = stack_pop(&mut stack, &op, &[Types::Any])?;
+                        if !t.is_int() && !matches!(t, Types::Enum(_)) {
+                            lerror!(&op.loc, "Expected an int or an enum to match on, but got {t}");
+                            op.note_expansion();
+                            bail!("");
+                        }
+                        blocks.push(Block::Match(stack.clone(), None, false));
+                    },
+                    KeywordType::Case |
+                    KeywordType::Default => {
+                        if keyword == KeywordType::Default {
+                            if let Some(Block::Match(_, _, has_default)) = blocks.last_mut() {
+                                *has_default = true;
+                            }
+                        }
+                        blocks.push(Block::Arm);
+                    },
+                    KeywordType::End => {
+                        match blocks.pop() {
+                            Some(Block::If(before, mut branches)) => {
+                                add_branch(&mut branches, &stack, reachable, &op, "an 'if'")?;
+                                if let Some(left) = branches.as_ref().filter(|left| !same_types(left, &before)) {
+                                    lerror!(&op.loc, "An 'if' without 'else' cannot change the stack, expected {}, but got {}", show(&before), show(left));
+                                    op.note_expansion();
+                                    bail!("");
+                                }
+                                stack = branches.map_or(before.clone(), |left| join(&before, &left));
+                                reachable = true;
+                            }
+                            Some(Block::Else(mut branches)) => {
+                                add_branch(&mut branches, &stack, reachable, &op, "an 'if'")?;
+                                // if no branch gets here the code after the 'end' cant be reached either
+                                if let Some(left) = branches {
+                                    stack = left;
+                                    reachable = true;
+                                }
+                            }
+                            Some(Block::Loop(before, exit)) => {
+                                if reachable && !same_types(&stack, &before) {
+                                    lerror!(&op.loc, "A loop body has to leave the stack like it was before the condition, expected {}, but got {}", show(&before), show(&stack));
+                                    op.note_expansion();
+                                    bail!("");
+                                }
+                                stack = exit;
+                                reachable = true;
+                            }
+                            Some(Block::Arm) => {
+                                let Some(Block::Match(before, branches, _)) = blocks.last_mut() else { unreachable!() };
+                                add_branch(branches, &stack, reachable, &op, "a 'match'")?;
+                                stack = before.clone();
+                                reachable = true;
+                            }
+                            Some(Block::Match(before, branches, has_default)) => {
+                                if has_default {
+                                    // if no arm gets here the code after the 'end' cant be reached either
+                                    match branches {
+                                        Some(left) => stack = left,
+                                        None => reachable = false
+                                    }
+                                } else {
+                                    if let Some(left) = branches.as_ref().filter(|left| !same_types(left, &before)) {
+                                        lerror!(&op.loc, "A 'match' without 'default' cannot change the stack, expected {}, but got {}", show(&before), show(left));
+                                        op.note_expansion();
+                                        bail!("");
+                                    }
+                                    stack = branches.map_or(before.clone(), |left| join(&before, &left));
+                                }
+                            }
+                            Some(Block::While(_) | Block::Elif(..)) => unreachable!(),
+                            None => ()
+                        }
+                    },
+                    KeywordType::Break |
+                    KeywordType::Continue => {
+                        let Some((before, exit)) = blocks.iter().rev().find_map(|b| match b {
+                            Block::Loop(before, exit) => Some((before, exit)),
+                            _ => None
+                        }) else { unreachable!() };
+
+                        let (expected, what) = if keyword == KeywordType::Break {
+                            (exit, "when the loop exits")
+                        } else {
+                            (before, "before the loop condition")
+                        };
+                        if !same_types(&stack, expected) {
+                            lerror!(&op.loc, "'{}' has to leave the stack like it is {what}, expected {}, but got {}", op.typ.human(), show(expected), show(&stack));
+                            op.note_expansion();
+                            bail!("");
+                        }
+                        reachable = false;
                     },
 
                     KeywordType::FunctionDefExported |
@@ -67,12 +243,12 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
                         // println!("{:?}", name);
                         if let Some(p) = rtokens.pop() {
                             if p.typ != OpType::Instruction(InstructionType::With){
-                                lerror!(&op.loc, "Expected {:?}, got {:?}", OpType::Instruction(InstructionType::With), p.typ);
+                                lerror!(&op.loc, "Expected {}, got {}", OpType::Instruction(InstructionType::With).human(), p.typ.human());
                                 bail!("");
                             }
 
                         } else {
-                            lerror!(&op.loc, "Expected {:?}, got nothing", OpType::Instruction(InstructionType::With));
+                            lerror!(&op.loc, "Expected {}, got nothing", OpType::Instruction(InstructionType::With).human());
                             bail!("");
                         }
                         
@@ -85,6 +261,22 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
                         let mut return_args = false;
                         while p.as_ref().is_some() {
                             let op = p.as_ref().unwrap();
+                            if let OpType::Instruction(t @ (InstructionType::TypeEnum | InstructionType::TypeStruct | InstructionType::TypeFn | InstructionType::TypeVar | InstructionType::TypeSized | InstructionType::TypeArray)) = &op.typ {
+                                let t = match t {
+                                    InstructionType::TypeSized => sized_type(op),
+                                    InstructionType::TypeArray => Types::from_element(&op.text),
+                                    InstructionType::TypeEnum => Types::Enum(op.text.clone()),
+                                    InstructionType::TypeStruct => Types::Struct(op.text.clone()),
+                                    InstructionType::TypeVar => Types::Var(op.text.clone()),
+                                    _ => Types::from_signature(&op.text)
+                                };
+                                if return_args {
+                                    func.returns.push(t);
+                                } else {
+                                    func.args.push(t);
+                                }
+                            }
+
                             if op.typ == OpType::Instruction(InstructionType::TypeBool) ||
                                 op.typ == OpType::Instruction(InstructionType::TypeInt) ||
                                 op.typ == OpType::Instruction(InstructionType::TypePtr) ||
@@ -102,6 +294,7 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
                                         } else {
                                             func.args = vec![Types::Void];
                                             return_args = true;
+                                            p = rtokens.pop();
                                             continue;
                                         }
                                         Types::Void
@@ -128,11 +321,18 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
                             p = rtokens.pop();
                         };
 
+                        // every call has to be able to tell what the type variables are from its arguments
+                        let bound = type_vars(&func.args);
+                        if let Some(v) = type_vars(&func.returns).into_iter().find(|v| !bound.contains(v)) {
+                            lerror!(&func.loc, "Type variable '{} of '{}' is only returned, it has to be in the arguments too", v, name);
+                            bail!("");
+                        }
+
 
+                        let code_ip = end_ip - rtokens.len();
                         let mut code: Vec<Operator> = Vec::new();
 
-                        while !rtokens.is_empty() {
-                            let op = rtokens.pop().unwrap();
+                        while let Some(op) = rtokens.pop() {
 
                             if op.typ == OpType::Keyword(KeywordType::FunctionDone) {
                                 break;
@@ -149,9 +349,10 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
                             continue;
                         }
                         functions.insert(name.clone(), func.clone());
-                        let (ret_typs, _, _) = typecheck(code, args, Some(ts.clone()), functions.clone(), constants.clone())?;
-                        if ret_typs != func.returns && !func.returns.contains(&Types::Void){
-                            lerror!(&func.loc, "Expected {:?}, but got {:?}", func.returns, ret_typs);
+                        let returns = if func.returns.contains(&Types::Void) { Vec::new() } else { func.returns.clone() };
+                        let (ret_typs, _, _) = typecheck(code, args, Some(ts.clone()), functions.clone(), constants.clone(), code_ip, Some(&returns), lowering)?;
+                        if !fits(&ret_typs, &func.returns) && !func.returns.contains(&Types::Void){
+                            lerror!(&func.loc, "Expected {}, but got {}", show(&func.returns), show(&ret_typs));
                             bail!("");
                         }
 
@@ -161,15 +362,26 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
                         stack_snapshots.push(stack.clone());
                     }
 
-                    KeywordType::Else |
-                    KeywordType::End |
-                    KeywordType::While |
                     KeywordType::Include |
                     KeywordType::Constant |
                     KeywordType::Memory => (),
                     KeywordType::ConstantDef => {
                         // println!("defined constant");
-                        constants.insert(op.text, Constant { loc: op.loc.clone(), types: vec![Types::U64] });
+                        let types = match op.tok_typ {
+                            TokenType::String => vec![Types::U64, Types::Ptr],
+                            TokenType::CString => vec![Types::Ptr],
+                            // constant names cant have a '.', only enum variants do
+                            _ => match op.text.rsplit_once('.') {
+                                Some((name, _)) => vec![Types::Enum(name.to_string())],
+                                // like a literal, a constant that evaluated to a negative value is signed
+                                #[allow(clippy::cast_possible_wrap)]
+                                None => {
+                                    let value = i128::from(op.value as i64);
+                                    vec![Types::Lit { value: Some(value), signed: value < 0 }]
+                                }
+                            }
+                        };
+                        constants.insert(op.text, Constant { loc: op.loc.clone(), types });
                         
                     },
                     KeywordType::FunctionThen |
@@ -180,13 +392,25 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
                         println!("{:?}", op);
                         unreachable!()
                     },
-                    KeywordType::Struct => todo!(),
+                    KeywordType::Struct |
+                    KeywordType::Alloc |
+                    KeywordType::Asm |
+                    KeywordType::Macro |
+                    KeywordType::CondIf |
+                    KeywordType::CondThen |
+                    KeywordType::CondElse |
+                    KeywordType::CondEnd |
+                    KeywordType::Priv |
+                    KeywordType::Enum => unreachable!(),
                 }
             },
             OpType::Instruction(instruction) => {
                 match instruction {
                     InstructionType::PushInt => {
-                        stack.push(Types::U64);
+                        // negative literals are stored as their two's complement
+                        #[allow(clippy::cast_possible_wrap)]
+                        let value = if op.text.starts_with('-') { i128::from(op.value as i64) } else { op.value as i128 };
+                        stack.push(Types::Lit { value: Some(value), signed: value < 0 });
                     },
                     InstructionType::PushStr => {
                         stack.push(Types::U64);
@@ -200,10 +424,13 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
                         stack_pop(&mut stack, &op, &[Types::Any])?;
                     },
                     InstructionType::Print => {
-                        stack_pop(&mut stack, &op, &[Types::U64])?;
+                        if stack_pop(&mut stack, &op, &[Types::U64, Types::I64])?.is_signed() {
+                            lowering.signed.push(ip);
+                        }
                     },
                     InstructionType::Dup => {
                         let a = stack_pop(&mut stack, &op, &[Types::Any])?;
+                        stack.push(a.clone());
                         stack.push(a);
                     },
                     InstructionType::Rot => {
@@ -229,14 +456,38 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
                     },
                     InstructionType::Minus |
                     InstructionType::Plus |
-                    InstructionType::Band |
                     InstructionType::Bor |
-                    InstructionType::Shr |
-                    InstructionType::Shl |
                     InstructionType::Mul => {
+                        let a = stack_pop(&mut stack, &op, &[Types::U64, Types::I64])?;
+                        let b = stack_pop(&mut stack, &op, &[Types::U64, Types::I64])?;
+                        stack.push(int_result(&a, &b));
+                    },
+                    InstructionType::Band => {
+                        // the only narrowing without a cast: masking with a literal that is not negative
+                        // leaves at most the mask, so `x 255 band` is a u8 and `x 256 band` a u16
+                        let a = stack_pop(&mut stack, &op, &[Types::U64, Types::I64])?;
+                        let b = stack_pop(&mut stack, &op, &[Types::U64, Types::I64])?;
+                        let masked = [&a, &b].into_iter().find_map(|t| match t {
+                            Types::Lit { value: Some(v), .. } if *v >= 0 => {
+                                let lit = Types::Lit { value: Some(*v), signed: false };
+                                [Types::U8, Types::U16, Types::U32].into_iter().find(|t| lit.widens_to(t))
+                            },
+                            _ => None
+                        });
+                        match masked {
+                            Some(t) if !matches!((&a, &b), (Types::Lit { .. }, Types::Lit { .. })) => stack.push(t),
+                            _ => stack.push(int_result(&a, &b))
+                        }
+                    },
+                    InstructionType::Shr |
+                    InstructionType::Shl => {
+                        // a signed value is shifted arithmetically
                         stack_pop(&mut stack, &op, &[Types::U64])?;
-                        stack_pop(&mut stack, &op, &[Types::U64])?;
-                        stack.push(Types::U64);
+                        let b = stack_pop(&mut stack, &op, &[Types::U64, Types::I64])?;
+                        if b.is_signed() {
+                            lowering.signed.push(ip);
+                        }
+                        stack.push(int_result(&b, &b));
                     },
                     InstructionType::Equals |
                     InstructionType::Gt |
@@ -244,28 +495,62 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
                     InstructionType::Ge |
                     InstructionType::Le |
                     InstructionType::NotEquals => {
-                        stack_pop(&mut stack, &op, &[Types::U64])?;
-                        stack_pop(&mut stack, &op, &[Types::U64])?;
+                        // ints compare with ints one of them widens to, enums only with the same enum
+                        let a = stack_pop(&mut stack, &op, &[Types::Any])?;
+                        let b = stack_pop(&mut stack, &op, &[Types::Any])?;
+                        let comparable = |t: &Types| t.is_int() || matches!(t, Types::Enum(_) | Types::Any);
+                        let ints = a.is_int() && b.is_int() && (a.widens_to(&b) || b.widens_to(&a));
+                        if !comparable(&a) || !comparable(&b) || !(same_type(&a, &b) || ints) {
+                            lerror!(&op.loc, "Cannot compare {b} with {a}");
+                            op.note_expansion();
+                            bail!("");
+                        }
+                        if int_result(&a, &b).is_signed() {
+                            lowering.signed.push(ip);
+                        }
                         stack.push(Types::Bool);
                     },
                     InstructionType::DivMod => {
-                        stack_pop(&mut stack, &op, &[Types::U64])?;
-                        stack_pop(&mut stack, &op, &[Types::U64])?;
-                        stack.push(Types::U64);
-                        stack.push(Types::U64);
+                        let a = stack_pop(&mut stack, &op, &[Types::U64, Types::I64])?;
+                        let b = stack_pop(&mut stack, &op, &[Types::U64, Types::I64])?;
+                        let t = int_result(&a, &b);
+                        if t.is_signed() {
+                            lowering.signed.push(ip);
+                        }
+                        stack.push(t.clone());
+                        stack.push(t);
                     },
                     InstructionType::Read8 |
                     InstructionType::Read32 |
                     InstructionType::Read64 => {
                         stack_pop(&mut stack, &op, &[Types::Ptr])?;
-                        stack.push(Types::U64);
+                        stack.push(memory_width(&instruction));
                     },
                     InstructionType::Write8 |
                     InstructionType::Write32 |
                     InstructionType::Write64 => {
-                        stack_pop(&mut stack, &op, &[Types::U64])?;
+                        stack_pop(&mut stack, &op, &storable(&memory_width(&instruction)))?;
                         stack_pop(&mut stack, &op, &[Types::Ptr])?;
                     },
+                    InstructionType::Index => {
+                        stack_pop(&mut stack, &op, &[Types::U64])?;
+                        let (elem, count) = pointee(&mut stack, &op)?;
+                        lowering.elements.push((ip, elem.element_name(), count.unwrap_or(0)));
+                        stack.push(Types::PtrTo(Box::new(elem), None));
+                    },
+                    InstructionType::IndexRead => {
+                        stack_pop(&mut stack, &op, &[Types::U64])?;
+                        let (elem, count) = pointee(&mut stack, &op)?;
+                        lowering.elements.push((ip, elem.element_name(), count.unwrap_or(0)));
+                        stack.push(elem);
+                    },
+                    InstructionType::IndexWrite => {
+                        let value = stack_pop(&mut stack, &op, &[Types::Any])?;
+                        stack_pop(&mut stack, &op, &[Types::U64])?;
+                        let (elem, count) = pointee(&mut stack, &op)?;
+                        check_store(&value, &elem, &op)?;
+                        lowering.elements.push((ip, elem.element_name(), count.unwrap_or(0)));
+                    },
                     InstructionType::Syscall0 => {
                         stack_pop(&mut stack, &op, &[Types::U64])?;
                         stack.push(Types::U64);
@@ -327,11 +612,32 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
                         stack_pop(&mut stack, &op, &[Types::Any])?;
                         stack.push(Types::U64);
                     },
+                    InstructionType::CastSized => {
+                        stack_pop(&mut stack, &op, &[Types::Any])?;
+                        stack.push(sized_type(&op));
+                    },
                     InstructionType::CastVoid => {
                         stack_pop(&mut stack, &op, &[Types::Any])?;
                         stack.push(Types::Any);
                     },
-                    InstructionType::MemUse => {
+                    InstructionType::CastEnum => {
+                        stack_pop(&mut stack, &op, &[Types::U64])?;
+                        stack.push(Types::Enum(op.text));
+                    },
+                    InstructionType::CastStruct => {
+                        stack_pop(&mut stack, &op, &[Types::Ptr])?;
+                        stack.push(Types::Struct(op.text));
+                    },
+                    InstructionType::CastFn => {
+                        stack_pop(&mut stack, &op, &[Types::Ptr])?;
+                        stack.push(Types::from_signature(&op.text));
+                    },
+                    InstructionType::CastArray => {
+                        stack_pop(&mut stack, &op, &[Types::Ptr])?;
+                        stack.push(Types::from_element(&op.text));
+                    },
+                    InstructionType::MemUse |
+                    InstructionType::LocalMemUse => {
                         stack.push(Types::Ptr);
                     },
                     InstructionType::FnCall  => {
@@ -344,32 +650,50 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
 
                         // in_function = (op.text.clone(), f.clone(), op.loc.clone());
 
-                        let mut s = stack.clone();
-                        let mut a = f.args.clone();
-                        // s.reverse();
-                        a.reverse();
-
-                        for t in a{
-                            if let Some(s2) = s.pop(){
-                                if t != s2 {
-                                    lerror!(&op.loc, "Expected {:?}, but got {:?}", t, s2);
-                                    bail!("");
-                                }
-                            } else {
-                                lerror!(&op.loc, "Expected {:?}, but got nothing", t);
-                                bail!("");
-                            }
-                        }
-
-                        
+                        let Types::Fn(a, r) = f.pointer() else { unreachable!() };
+                        call(&mut stack, &op, &a, &r)?;
+                    }
+                    InstructionType::FnAddr => {
+                        let Some(f) = functions.get(&op.text) else {
+                            lerror!(&op.loc, "Could not find function {}", op.text);
+                            bail!("");
+                        };
+                        stack.push(f.pointer());
+                    }
+                    InstructionType::Call => {
+                        let t = stack_pop(&mut stack, &op, &[Types::Any])?;
+                        let Types::Fn(a, r) = t else {
+                            lerror!(&op.loc, "Expected a function pointer to call, but got {t}");
+                            op.note_expansion();
+                            bail!("");
+                        };
+                        call(&mut stack, &op, &a, &r)?;
                     }
-                    InstructionType::Return |
+                    InstructionType::Return => {
+                        let Some(returns) = returns else {
+                            lerror!(&op.loc, "'return' can only be used in a function");
+                            op.note_expansion();
+                            bail!("");
+                        };
+                        if !fits(&stack, returns) {
+                            lerror!(&op.loc, "'return' has to leave what the function returns, expected {}, but got {}", show(returns), show(&stack));
+                            op.note_expansion();
+                            bail!("");
+                        }
+                        reachable = false;
+                    },
                     InstructionType::None |
                     InstructionType::TypeBool |
                     InstructionType::TypePtr |
                     InstructionType::TypeInt |
+                    InstructionType::TypeSized |
                     InstructionType::TypeVoid |
                     InstructionType::TypeAny |
+                    InstructionType::TypeEnum |
+                    InstructionType::TypeStruct |
+                    InstructionType::TypeFn |
+                    InstructionType::TypeArray |
+                    InstructionType::TypeVar |
                     InstructionType::Returns |
                     InstructionType::With => (),
                     InstructionType::ConstUse => {
@@ -377,7 +701,51 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
                         let mut c = constants.get(&op.text).unwrap().clone();
                         stack.append(&mut c.types);
                     },
-                    InstructionType::StructUse => todo!(),
+                    InstructionType::Field => {
+                        stack_pop(&mut stack, &op, &[Types::Struct(op.text.clone())])?;
+                        stack.push(Types::Ptr);
+                    },
+                    InstructionType::FieldRead => {
+                        let (elem, _) = pointee(&mut stack, &op)?;
+                        lowering.elements.push((ip, elem.element_name(), 0));
+                        stack.push(elem);
+                    },
+                    InstructionType::FieldWrite => {
+                        let value = stack_pop(&mut stack, &op, &[Types::Any])?;
+                        let (elem, _) = pointee(&mut stack, &op)?;
+                        check_store(&value, &elem, &op)?;
+                        lowering.elements.push((ip, elem.element_name(), 0));
+                    },
+                    InstructionType::InlineAsm => {
+                        let (ins, outs) = asm_signature(&mut rtokens, &op)?;
+
+                        let fallback_ip = end_ip - rtokens.len();
+                        let mut fallback: Vec<Operator> = Vec::new();
+                        let mut depth = 0;
+                        while let Some(o) = rtokens.pop() {
+                            match o.typ {
+                                OpType::Keyword(KeywordType::If | KeywordType::While | KeywordType::Match | KeywordType::Case | KeywordType::Default) => depth += 1,
+                                OpType::Keyword(KeywordType::End) if depth == 0 => break,
+                                OpType::Keyword(KeywordType::End) => depth -= 1,
+                                _ => ()
+                            }
+                            fallback.push(o);
+                        }
+
+                        if !fallback.is_empty() {
+                            let (ret_typs, _, _) = typecheck(fallback, args, Some(ins.clone()), functions.clone(), constants.clone(), fallback_ip, returns, lowering)?;
+                            if !fits(&ret_typs, &outs) {
+                                lerror!(&op.loc, "Asm fallback expected to return {}, but got {}", show(&outs), show(&ret_typs));
+                                op.note_expansion();
+                                bail!("");
+                            }
+                        }
+
+                        for t in ins.iter().rev() {
+                            stack_pop(&mut stack, &op, std::slice::from_ref(t))?;
+                        }
+                        stack.extend(outs);
+                    },
                 }
             },
             OpType::Internal(t) => panic!("{t:?}"),
@@ -386,21 +754,242 @@ pub fn typecheck(ops: Vec<Operator>, args: &Args, init_types: Option<Vec<Types>>
 
         
     }
-    
+
+    // every path returned before the end, with what the function returns
+    if let Some(returns) = returns.filter(|_| !reachable) {
+        stack = returns.to_vec();
+    }
     Ok((stack, functions, constants))
 }
 
 
 
+/// Reads the `with ... returns ... then` signature following an asm block
+fn asm_signature(rtokens: &mut Vec<Operator>, op: &Operator) -> Result<(Vec<Types>, Vec<Types>)> {
+    let mut ins = Vec::new();
+    let mut outs = Vec::new();
+    let mut return_args = false;
+    while let Some(t) = rtokens.pop() {
+        let typ = match t.typ {
+            OpType::Instruction(InstructionType::TypeInt) => Types::U64,
+            OpType::Instruction(InstructionType::TypeSized) => sized_type(&t),
+            OpType::Instruction(InstructionType::TypeBool) => Types::Bool,
+            OpType::Instruction(InstructionType::TypePtr) => Types::Ptr,
+            OpType::Instruction(InstructionType::TypeAny) => Types::Any,
+            OpType::Instruction(InstructionType::TypeEnum) => Types::Enum(t.text),
+            OpType::Instruction(InstructionType::TypeStruct) => Types::Struct(t.text),
+            OpType::Instruction(InstructionType::TypeFn) => Types::from_signature(&t.text),
+            OpType::Instruction(InstructionType::TypeArray) => Types::from_element(&t.text),
+            OpType::Instruction(InstructionType::Returns) => {
+                return_args = true;
+                continue;
+            }
+            OpType::Keyword(KeywordType::FunctionThen) => return Ok((ins, outs)),
+            // void is the same as no types
+            _ => continue
+        };
+
+        if return_args {
+            outs.push(typ);
+        } else {
+            ins.push(typ);
+        }
+    }
+
+    lerror!(&op.loc, "Expected 'then' after the asm signature, got nothing");
+    bail!("")
+}
+
+/// Checks the stack a branch of an 'if' leaves against the branches before it
+fn add_branch(branches: &mut Option<Vec<Types>>, stack: &[Types], reachable: bool, op: &Operator, block: &str) -> Result<()> {
+    if !reachable {
+        return Ok(());
+    }
+    match branches {
+        Some(left) if !same_types(left, stack) => {
+            lerror!(&op.loc, "Every branch of {block} has to leave the same stack, got {} and {}", show(left), show(stack));
+            op.note_expansion();
+            bail!("");
+        }
+        Some(left) => *left = join(left, stack),
+        None => *branches = Some(stack.to_vec())
+    }
+    Ok(())
+}
+
+/// Pops the arguments of a call and pushes what it returns, a type variable is the type of the
+/// first argument it is used for
+fn call(stack: &mut Vec<Types>, op: &Operator, args: &[Types], returns: &[Types]) -> Result<()> {
+    let mut vars: HashMap<String, Types> = HashMap::new();
+    for t in args.iter().rev() {
+        if let Some(s2) = stack.pop(){
+            if !unify(t, &s2, &mut vars) {
+                lerror!(&op.loc, "Expected {}, but got {s2}", substitute(t, &vars));
+                op.note_expansion();
+                bail!("");
+            }
+        } else {
+            lerror!(&op.loc, "Expected {}, but got nothing", substitute(t, &vars));
+            op.note_expansion();
+            bail!("");
+        }
+    }
+
+    stack.extend(returns.iter().map(|t| substitute(t, &vars)));
+    Ok(())
+}
+
+fn unify(t: &Types, s: &Types, vars: &mut HashMap<String, Types>) -> bool {
+    match (t, s) {
+        (Types::Var(v), s) => match vars.get(v) {
+            Some(bound) => same_type(bound, s),
+            None => {
+                vars.insert(v.clone(), forget(std::slice::from_ref(s)).remove(0));
+                true
+            }
+        },
+        (Types::Fn(ta, tr), Types::Fn(sa, sr)) => {
+            ta.len() == sa.len() && tr.len() == sr.len() &&
+                ta.iter().zip(sa).chain(tr.iter().zip(sr)).all(|(t, s)| unify(t, s, vars))
+        },
+        (t, s) => same_type(t, s) || s.widens_to(t)
+    }
+}
+
+fn substitute(t: &Types, vars: &HashMap<String, Types>) -> Types {
+    match t {
+        Types::Var(v) => vars.get(v).cloned().unwrap_or_else(|| t.clone()),
+        Types::Fn(a, r) => Types::Fn(
+            a.iter().map(|t| substitute(t, vars)).collect(),
+            r.iter().map(|t| substitute(t, vars)).collect()
+        ),
+        t => t.clone()
+    }
+}
+
+fn type_vars(types: &[Types]) -> Vec<&str> {
+    types.iter().flat_map(|t| match t {
+        Types::Var(v) => vec![v.as_str()],
+        Types::Fn(a, r) => [type_vars(a), type_vars(r)].concat(),
+        _ => Vec::new()
+    }).collect()
+}
+
+/// The type of an int operation on `a` and `b`, always 64 bits and signed if one of them is,
+/// without a width if neither of them has one
+fn int_result(a: &Types, b: &Types) -> Types {
+    let signed = a.is_signed() || b.is_signed();
+    match (a, b) {
+        (Types::Lit { .. }, Types::Lit { .. }) => Types::Lit { value: None, signed },
+        _ if signed => Types::I64,
+        _ => Types::U64
+    }
+}
+
+/// The stack where two paths that left `a` and `b` meet, ints without a width take the type
+/// the other path gave them
+fn join(a: &[Types], b: &[Types]) -> Vec<Types> {
+    a.iter().zip(b).map(|(a, b)| match (a, b) {
+        (Types::Lit { .. }, Types::Lit { .. }) if a != b => Types::Lit { value: None, signed: a.is_signed() || b.is_signed() },
+        (Types::Lit { .. }, b) => b.clone(),
+        (a, _) => a.clone()
+    }).collect()
+}
+
+/// The stack with the values of literals forgotten, for when they can change
+fn forget(stack: &[Types]) -> Vec<Types> {
+    stack.iter().map(|t| match t {
+        Types::Lit { signed, .. } => Types::Lit { value: None, signed: *signed },
+        t => t.clone()
+    }).collect()
+}
+
+/// If the stack `got` can be returned where `want` is expected, ints can get wider on the way
+fn fits(got: &[Types], want: &[Types]) -> bool {
+    got.len() == want.len() && got.iter().zip(want).all(|(g, w)| g.widens_to(w))
+}
+
+/// What can be written to a memory of type `t`, any int that is not wider than it
+fn storable(t: &Types) -> Vec<Types> {
+    if !t.is_int() {
+        return vec![t.clone()];
+    }
+    [Types::U8, Types::U16, Types::U32, Types::U64, Types::I8, Types::I16, Types::I32, Types::I64]
+        .into_iter()
+        .filter(|i| i.get_size() <= t.get_size())
+        .collect()
+}
+
+/// Pops the typed pointer an index, `@` or `!` works on, with the type it points to and how
+/// many there are
+fn pointee(stack: &mut Vec<Types>, op: &Operator) -> Result<(Types, Option<usize>)> {
+    match stack_pop(stack, op, &[Types::Any])? {
+        Types::PtrTo(t, count) => Ok((*t, count)),
+        t => {
+            lerror!(&op.loc, "'{}' needs an array or a struct field, but got {t}", op.typ.human());
+            op.note_expansion();
+            bail!("");
+        }
+    }
+}
+
+/// Checks that `value` can be written to a memory of type `t`
+fn check_store(value: &Types, t: &Types, op: &Operator) -> Result<()> {
+    let types = storable(t);
+    if !types.iter().any(|t| value.widens_to(t)) {
+        lerror!(&op.loc, "Expected {}, but got {value}", any_of(&types));
+        op.note_expansion();
+        bail!("");
+    }
+    Ok(())
+}
+
+/// The type of memory `read8` and friends work on
+fn memory_width(instruction: &InstructionType) -> Types {
+    match instruction {
+        InstructionType::Read8 | InstructionType::Write8 => Types::U8,
+        InstructionType::Read32 | InstructionType::Write32 => Types::U32,
+        _ => Types::U64
+    }
+}
+
+/// The type of a sized int op, `u8` or `cast(u8)`
+fn sized_type(op: &Operator) -> Types {
+    let name = op.text.strip_prefix("cast(").and_then(|t| t.strip_suffix(')')).unwrap_or(&op.text);
+    Types::from_string(&name).unwrap()
+}
+
+/// Any matches every type, and ints without a width every int they fit in
+fn same_type(a: &Types, b: &Types) -> bool {
+    let lit = |a: &Types, b: &Types| matches!(a, Types::Lit { .. }) && a.widens_to(b);
+    a == b || *a == Types::Any || *b == Types::Any || lit(a, b) || lit(b, a)
+}
+
+fn same_types(a: &[Types], b: &[Types]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_type(a, b))
+}
+
+/// `[int, ptr]`, a stack in an error
+fn show(types: &[Types]) -> String {
+    format!("[{}]", types.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))
+}
+
+/// `u8 or i8`, what an op takes in an error
+fn any_of(types: &[Types]) -> String {
+    types.iter().map(ToString::to_string).collect::<Vec<_>>().join(" or ")
+}
+
 fn stack_pop(v: &mut Vec<Types>, op: &Operator, t: &[Types]) -> Result<Types> {
     if v.is_empty() {
-        lerror!(&op.loc, "Expected {:?}, but got nothing", t);
+        lerror!(&op.loc, "Expected {}, but got nothing", any_of(t));
+        op.note_expansion();
         bail!("");
     }
     let r = v.pop().unwrap();
 
-    if !t.contains(&r) && t[0] != Types::Any {
-        lerror!(&op.loc, "Expected {:?}, but got {:?}", t, r);
+    if !t.iter().any(|t| r.widens_to(t)) && t[0] != Types::Any {
+        lerror!(&op.loc, "Expected {}, but got {r}", any_of(t));
+        op.note_expansion();
         bail!("");
     }
 
@@ -1,29 +1,56 @@
+use std::fmt;
+use std::sync::Mutex;
 
+// every file that was lexed, `Loc::file_id` indexes into this
+static FILES: Mutex<Vec<String>> = Mutex::new(Vec::new());
 
+/// Span of source code, `start..end` are byte offsets into the file, `ln` and `col` are where it starts
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Loc {
     pub ln: usize,
     pub col: usize,
-    pub file: String
+    pub file: String,
+    pub file_id: usize,
+    pub start: usize,
+    pub end: usize,
 }
 
 impl Loc {
-    pub fn new(file: String, ln: usize, col: usize) -> Self {
+    pub fn new(file: &str, start: usize, ln: usize, col: usize) -> Self {
         Self {
             ln,
             col,
-            file,
+            file: file.to_string(),
+            file_id: Self::file_id(file),
+            start,
+            end: start,
         }
     }
+
+    /// Id of a file, files get a new one the first time they are seen
+    pub fn file_id(file: &str) -> usize {
+        let mut files = FILES.lock().unwrap();
+        if let Some(id) = files.iter().position(|f| f == file) {
+            return id;
+        }
+        files.push(file.to_string());
+        files.len() - 1
+    }
+
+    /// The `(file, line, col)` location the diagnostics use
+    pub fn as_tuple(&self) -> crate::definitions::Loc {
+        (self.file.clone(), self.ln, self.col)
+    }
 }
 
-impl ToString for Loc {
-    fn to_string(&self) -> String {
-        format!("{}:{}:{}", self.file, self.ln, self.col)
+impl fmt::Display for Loc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.ln, self.col)
     }
 }
 
-impl Into<String> for Loc {
-    fn into(self) -> String {
-        self.to_string()
+impl From<Loc> for String {
+    fn from(loc: Loc) -> Self {
+        loc.to_string()
     }
-}
\ No newline at end of file
+}
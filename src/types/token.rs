@@ -1,3 +1,5 @@
+#![allow(dead_code)] //TODO: replace definitions::Token with this
+
 use super::common::Loc;
 
 